@@ -0,0 +1,116 @@
+use actix_web::http::HeaderMap;
+
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::jwt::JwtAuth;
+use crate::session::SessionVariables;
+use crate::webhook::{forwarded_headers, AuthWebhook};
+
+/// The header that a request proves it's made by an admin with
+pub const ADMIN_SECRET_HEADER: &str = "x-hasura-admin-secret";
+
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+
+/// How the server decides who it is that's making a request
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+    // NOTE: without an admin secret, every request is trusted to be made by an admin
+    admin_secret: Option<String>,
+    // NOTE: in the JWT mode, the session of a request comes from the claims of its token
+    jwt: Option<JwtAuth>,
+    // NOTE: or, with an auth webhook, from the response of the webhook to its headers
+    webhook: Option<AuthWebhook>,
+    // NOTE: the role of the requests that come without any credentials, which
+    // would otherwise be turned away
+    unauthorized_role: Option<String>,
+}
+
+impl AuthConfig {
+    pub fn with_admin_secret(mut self, admin_secret: Option<String>) -> AuthConfig {
+        self.admin_secret = admin_secret;
+        self
+    }
+
+    pub fn with_jwt(mut self, jwt: Option<JwtAuth>) -> AuthConfig {
+        self.jwt = jwt;
+        self
+    }
+
+    pub fn with_webhook(mut self, webhook: Option<AuthWebhook>) -> AuthConfig {
+        self.webhook = webhook;
+        self
+    }
+
+    pub fn with_unauthorized_role(mut self, unauthorized_role: Option<String>) -> AuthConfig {
+        self.unauthorized_role = unauthorized_role;
+        self
+    }
+
+    /// Whether any of the credentials that the server looks for are a part of the request
+    fn has_credentials(&self, headers: &HeaderMap) -> bool {
+        headers.contains_key(ADMIN_SECRET_HEADER)
+            || (self.jwt.is_some() && headers.contains_key(AUTHORIZATION_HEADER))
+            || (self.webhook.is_some() && !forwarded_headers(headers).is_empty())
+    }
+
+    /// Checks that the request carries the admin secret, when the server has one
+    pub fn check_admin_secret(&self, headers: &HeaderMap) -> Result<(), GQLRSError> {
+        let admin_secret = match &self.admin_secret {
+            Some(admin_secret) => admin_secret,
+            None => return Ok(()),
+        };
+
+        match headers.get(ADMIN_SECRET_HEADER) {
+            Some(value) if constant_time_eq(value.as_bytes(), admin_secret.as_bytes()) => Ok(()),
+            Some(_) => Err(GQLRSError::new(GQLRSErrorType::AccessDenied(format!(
+                "invalid `{}`",
+                ADMIN_SECRET_HEADER
+            )))),
+            None => Err(GQLRSError::new(GQLRSErrorType::AccessDenied(format!(
+                "`{}` required, but not found",
+                ADMIN_SECRET_HEADER
+            )))),
+        }
+    }
+
+    /// Resolves the session that a GraphQL request runs with. The session variables of the
+    /// request (including the role) are only taken at their word once it's made by an admin,
+    /// and otherwise come from the token of the request in the JWT mode, or from the webhook.
+    /// A request without any credentials runs as the unauthorized role, when there's one
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<SessionVariables, GQLRSError> {
+        if let Some(unauthorized_role) = &self.unauthorized_role {
+            if !self.has_credentials(headers) {
+                return SessionVariables::with_role(unauthorized_role);
+            }
+        }
+
+        let is_admin_request =
+            self.admin_secret.is_some() && headers.contains_key(ADMIN_SECRET_HEADER);
+
+        match (&self.jwt, &self.webhook) {
+            (Some(jwt), _) if !is_admin_request => jwt.authenticate(headers),
+            (_, Some(webhook)) if !is_admin_request => webhook.authenticate(headers).await,
+            _ => {
+                self.check_admin_secret(headers)?;
+                SessionVariables::from_headers(headers)
+            }
+        }
+    }
+}
+
+/// Compares the secrets in a time that only depends on their lengths, so that the
+/// timing of the responses doesn't give away how much of a guess is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[test]
+fn constant_time_eq_compares_the_bytes() {
+    assert!(constant_time_eq(b"s3cr3t", b"s3cr3t"));
+    assert!(!constant_time_eq(b"s3cr3t", b"s3cr3T"));
+    assert!(!constant_time_eq(b"s3cr3t", b"s3cr3"));
+    assert!(constant_time_eq(b"", b""));
+}
@@ -0,0 +1,165 @@
+use log::{debug, info, warn};
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::Json;
+use postgres::Client;
+use std::time::Duration;
+
+use crate::context::AppState;
+use crate::db;
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::metadata::Metadata;
+use crate::utils::squote;
+
+// NOTE: the catalog holds a single row with the whole of the metadata, much like
+// the `hdb_catalog.hdb_metadata` table of the Hasura GraphQL Engine
+const METADATA_ROW_ID: i32 = 1;
+
+fn db_error(err: postgres::Error) -> GQLRSError {
+    GQLRSError::new(GQLRSErrorType::DBError(format!("{:?}", err)))
+}
+
+/// The channel on which every change to the metadata stored in the catalog is notified,
+/// with the resource version of the new metadata as the payload
+pub const METADATA_CHANGE_CHANNEL: &str = "hdb_metadata_change";
+
+const METADATA_SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// NOTE: the servers that share the catalog may well start at the same time, so they take
+// turns at initialising it, by way of the transaction level advisory lock on this key
+// (which is `hdb_cat` in ASCII)
+const CATALOG_INIT_LOCK_KEY: i64 = 0x68_6462_5f63_6174;
+
+/// Creates the `hdb_catalog` schema and the table that the metadata is stored in,
+/// if they don't exist already
+pub fn initialise_catalog(client: &mut Client) -> Result<(), GQLRSError> {
+    // NOTE: the notification goes out only once the transaction that saved the
+    // metadata commits, so the listeners never see metadata that isn't saved yet.
+    // The trigger is only ever created when it's missing, since replacing it would lock
+    // the table and could miss the changes made in the meantime
+    let query = format!(
+        "CREATE SCHEMA IF NOT EXISTS hdb_catalog; \
+        CREATE TABLE IF NOT EXISTS hdb_catalog.hdb_metadata ( \
+            id integer PRIMARY KEY, \
+            metadata json NOT NULL \
+        ); \
+        CREATE OR REPLACE FUNCTION hdb_catalog.hdb_notify_metadata_change() RETURNS trigger AS $$ \
+        BEGIN \
+            PERFORM pg_notify({}, NEW.metadata->>'resource_version'); \
+            RETURN NULL; \
+        END; \
+        $$ LANGUAGE plpgsql; \
+        DO $$ BEGIN \
+            IF NOT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = 'hdb_notify_metadata_change' AND tgrelid = 'hdb_catalog.hdb_metadata'::regclass) THEN \
+                CREATE TRIGGER hdb_notify_metadata_change AFTER INSERT OR UPDATE ON hdb_catalog.hdb_metadata \
+                FOR EACH ROW EXECUTE PROCEDURE hdb_catalog.hdb_notify_metadata_change(); \
+            END IF; \
+        END $$;",
+        squote(METADATA_CHANGE_CHANNEL)
+    );
+
+    let mut transaction = client.transaction().map_err(db_error)?;
+    transaction
+        .execute(
+            "SELECT pg_advisory_xact_lock($1)",
+            &[&CATALOG_INIT_LOCK_KEY],
+        )
+        .map_err(db_error)?;
+    transaction
+        .batch_execute(query.as_str())
+        .map_err(db_error)?;
+    transaction.commit().map_err(db_error)
+}
+
+/// Fetches the metadata stored in the catalog, which is `None` until the
+/// metadata is saved for the first time
+pub fn fetch_metadata(client: &mut Client) -> Result<Option<Metadata>, GQLRSError> {
+    let row = client
+        .query_opt(
+            "SELECT metadata FROM hdb_catalog.hdb_metadata WHERE id = $1",
+            &[&METADATA_ROW_ID],
+        )
+        .map_err(db_error)?;
+
+    match row {
+        Some(row) => {
+            let metadata: Json<Metadata> = row.try_get("metadata").map_err(db_error)?;
+            Ok(Some(metadata.0))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Replaces the metadata stored in the catalog, as long as the stored metadata is still
+/// at the `previous_version`, i.e. it hasn't been modified by another server in the
+/// meantime. Since it's a single statement, the catalog either has all of the new
+/// metadata or none of it
+pub fn save_metadata(
+    client: &mut Client,
+    metadata: &Metadata,
+    previous_version: u64,
+) -> Result<(), GQLRSError> {
+    let saved_rows = client
+        .execute(
+            "INSERT INTO hdb_catalog.hdb_metadata (id, metadata) VALUES ($1, $2) \
+            ON CONFLICT (id) DO UPDATE SET metadata = EXCLUDED.metadata \
+            WHERE coalesce((hdb_metadata.metadata->>'resource_version')::bigint, 1) = $3",
+            &[
+                &METADATA_ROW_ID,
+                &Json(metadata),
+                &(previous_version as i64),
+            ],
+        )
+        .map_err(db_error)?;
+
+    if saved_rows == 0 {
+        return Err(GQLRSError::new(GQLRSErrorType::ResourceVersionConflict(
+            previous_version,
+        )));
+    }
+
+    Ok(())
+}
+
+/// Keeps the metadata of the server in sync with the catalog, so that the changes made to
+/// the metadata through any of the servers sharing the catalog are picked up by all of them
+pub fn start_metadata_sync(app_state: actix_web::web::Data<AppState>) {
+    let pg_pool = app_state.0.lock().unwrap().get_connection_pool().clone();
+    db::listen_on(
+        pg_pool,
+        METADATA_CHANGE_CHANNEL,
+        METADATA_SYNC_RETRY_INTERVAL,
+        move |listener| listen_for_metadata_changes(listener, &app_state),
+    );
+}
+
+fn listen_for_metadata_changes(listener: &mut Client, app_state: &AppState) {
+    // NOTE: the metadata could've changed while we weren't listening
+    sync_metadata(app_state);
+
+    let mut notifications = listener.notifications();
+    let mut notifications_iter = notifications.blocking_iter();
+    loop {
+        match notifications_iter.next() {
+            Ok(Some(notification)) => {
+                debug!(
+                    "The metadata in the catalog is now at resource version {}",
+                    notification.payload()
+                );
+                sync_metadata(app_state);
+            }
+            Ok(None) => return,
+            Err(err) => {
+                warn!("Failed to receive the metadata changes: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+fn sync_metadata(app_state: &AppState) {
+    match app_state.0.lock().unwrap().sync_metadata_from_catalog() {
+        Ok(true) => info!("Reloaded the metadata that was changed by another server"),
+        Ok(false) => {}
+        Err(err) => warn!("Failed to reload the metadata from the catalog: {}", err),
+    }
+}
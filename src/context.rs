@@ -1,11 +1,18 @@
-use postgres::NoTls;
+use log::warn;
+use postgres::{Client, NoTls};
 use r2d2::Pool;
 use r2d2_postgres::PostgresConnectionManager;
 use serde::Serialize;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use crate::metadata::{Metadata, MetadataResult, QualifiedTable};
+use crate::auth::AuthConfig;
+use crate::catalog;
+use crate::db;
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::metadata::{DbTable, InconsistentObject, Metadata, MetadataResult, QualifiedTable};
 
+// TODO: the `healthz` handler doesn't report `ERROR` just yet
+#[allow(dead_code)]
 #[derive(Debug, PartialEq, Clone, Serialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum Status {
@@ -13,13 +20,81 @@ pub enum Status {
     Error,
 }
 
-type PGPool = Pool<PostgresConnectionManager<NoTls>>;
+/// How the live queries of subscriptions find out that their results may have changed
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SubscriptionsMode {
+    /// Re-run every live query on an interval
+    Polling,
+    /// Install triggers on the tracked tables that `NOTIFY` on every change, and re-run
+    /// only the live queries that touch the changed tables
+    ListenNotify,
+}
+
+impl std::str::FromStr for SubscriptionsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "polling" => Ok(SubscriptionsMode::Polling),
+            "listen-notify" => Ok(SubscriptionsMode::ListenNotify),
+            _ => Err(format!(
+                "unknown subscriptions mode `{}`, expected one of polling, listen-notify",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the permissions of the roles are enforced
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PermissionsMode {
+    /// The engine applies the permissions in the metadata to the SQL that it generates
+    Engine,
+    /// Every query runs as the database role of the same name as the role of the request,
+    /// with the session variables as settings, and row level security policies take it from there
+    Rls,
+}
+
+impl std::str::FromStr for PermissionsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "engine" => Ok(PermissionsMode::Engine),
+            "rls" => Ok(PermissionsMode::Rls),
+            _ => Err(format!(
+                "unknown permissions mode `{}`, expected one of engine, rls",
+                s
+            )),
+        }
+    }
+}
+
+pub type PGPool = Pool<PostgresConnectionManager<NoTls>>;
+pub type PooledClient = r2d2::PooledConnection<PostgresConnectionManager<NoTls>>;
+
+/// Takes a connection out of the pool, and reports a failure to get one as a database error
+pub fn pooled_client(pg_pool: &PGPool) -> Result<PooledClient, GQLRSError> {
+    pg_pool
+        .get()
+        .map_err(|err| GQLRSError::new(GQLRSErrorType::DBError(format!("{:?}", err))))
+}
 
 #[derive(Clone, Debug)]
 pub struct ServerCtx {
     conn_pool: PGPool,
     metadata: Metadata,
     status: Status,
+    subscriptions_mode: SubscriptionsMode,
+    permissions_mode: PermissionsMode,
+    // NOTE: the handlers take the auth config out of the context to authenticate a request,
+    // since that may well need to wait on a webhook
+    auth_config: Arc<AuthConfig>,
+    // NOTE: when set, every change to the metadata is saved to the catalog
+    persist_metadata: bool,
+    // NOTE: these are the objects in the metadata that don't agree with the database,
+    // which are only ever kept around when a change explicitly allows for them
+    inconsistent_objects: Vec<InconsistentObject>,
 }
 
 impl ServerCtx {
@@ -28,17 +103,121 @@ impl ServerCtx {
             conn_pool: pg_pool,
             status: Status::Ok,
             metadata: Metadata::new(source_name),
+            subscriptions_mode: SubscriptionsMode::Polling,
+            permissions_mode: PermissionsMode::Engine,
+            auth_config: Arc::new(AuthConfig::default()),
+            persist_metadata: false,
+            inconsistent_objects: Vec::new(),
         }
     }
 
+    fn get_pg_client(&self) -> Result<PooledClient, GQLRSError> {
+        pooled_client(&self.conn_pool)
+    }
+
+    /// Loads the metadata stored in the catalog (if any), and from then on saves every
+    /// change to the metadata back to the catalog. The catalog has to be initialised first
+    pub fn load_metadata_from_catalog(&mut self) -> MetadataResult {
+        let mut pg_client = self.get_pg_client()?;
+
+        if let Some(stored_metadata) = catalog::fetch_metadata(&mut pg_client)? {
+            self.metadata = stored_metadata;
+        }
+        self.persist_metadata = true;
+
+        self.refresh_inconsistent_objects(&mut pg_client)
+    }
+
+    /// Replaces the metadata of the server with the one stored in the catalog, if the
+    /// stored metadata is newer. Returns whether the metadata was replaced
+    pub fn sync_metadata_from_catalog(&mut self) -> Result<bool, GQLRSError> {
+        let mut pg_client = self.get_pg_client()?;
+
+        match catalog::fetch_metadata(&mut pg_client)? {
+            Some(stored_metadata)
+                if stored_metadata.resource_version > self.metadata.resource_version =>
+            {
+                self.metadata = stored_metadata;
+                self.refresh_inconsistent_objects(&mut pg_client)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Reloads the metadata from the catalog (when it's persisted), and checks it
+    /// against the database all over again, e.g. after the database has been migrated
+    pub fn reload_metadata(&mut self) -> MetadataResult {
+        let mut pg_client = self.get_pg_client()?;
+
+        if self.persist_metadata {
+            if let Some(stored_metadata) = catalog::fetch_metadata(&mut pg_client)? {
+                self.metadata = stored_metadata;
+            }
+        }
+
+        self.refresh_inconsistent_objects(&mut pg_client)
+    }
+
+    fn refresh_inconsistent_objects(&mut self, pg_client: &mut Client) -> MetadataResult {
+        let db_tables = db::fetch_db_tables(pg_client)?;
+        self.metadata.set_db_columns(&db_tables);
+        self.inconsistent_objects = self.metadata.inconsistent_objects(&db_tables);
+
+        for inconsistent_object in self.inconsistent_objects.iter() {
+            warn!("{}", inconsistent_object.reason);
+        }
+
+        Ok(())
+    }
+
+    /// Fetches all the tables in the database, tracked or not
+    pub fn get_db_tables(&self) -> Result<Vec<DbTable>, GQLRSError> {
+        let mut pg_client = self.get_pg_client()?;
+        db::fetch_db_tables(&mut pg_client)
+    }
+
+    pub fn get_inconsistent_objects(&self) -> &[InconsistentObject] {
+        &self.inconsistent_objects
+    }
+
+    pub fn with_subscriptions_mode(mut self, subscriptions_mode: SubscriptionsMode) -> ServerCtx {
+        self.subscriptions_mode = subscriptions_mode;
+        self
+    }
+
+    pub fn get_subscriptions_mode(&self) -> SubscriptionsMode {
+        self.subscriptions_mode
+    }
+
+    pub fn with_permissions_mode(mut self, permissions_mode: PermissionsMode) -> ServerCtx {
+        self.permissions_mode = permissions_mode;
+        self
+    }
+
+    pub fn get_permissions_mode(&self) -> PermissionsMode {
+        self.permissions_mode
+    }
+
+    pub fn with_auth_config(mut self, auth_config: AuthConfig) -> ServerCtx {
+        self.auth_config = Arc::new(auth_config);
+        self
+    }
+
+    pub fn get_auth_config(&self) -> Arc<AuthConfig> {
+        self.auth_config.clone()
+    }
+
     pub fn get_status_json(&self) -> serde_json::Value {
         serde_json::json!({ "status": &self.status })
     }
 
+    #[allow(dead_code)]
     fn set_status_to_errored(&mut self) {
         self.status = Status::Error
     }
 
+    #[allow(dead_code)]
     fn set_status_to_healthy(&mut self) {
         self.status = Status::Ok
     }
@@ -47,21 +226,81 @@ impl ServerCtx {
         &self.conn_pool
     }
 
-    pub fn metadata_track_table(&mut self, table_info: QualifiedTable) -> MetadataResult {
-        self.metadata.track_table(table_info)
+    /// Applies the change to a copy of the metadata, checks it against the database and
+    /// saves it to the catalog. The metadata of the server is replaced only once all of
+    /// those have succeeded, so that a failed change leaves neither the server nor the
+    /// catalog modified. A change that makes any more of the metadata inconsistent is
+    /// rejected, unless `allow_inconsistent_metadata` is set. The change is given the
+    /// tables in the database along with the metadata.
+    pub fn update_metadata<F>(
+        &mut self,
+        update: F,
+        allow_inconsistent_metadata: bool,
+    ) -> MetadataResult
+    where
+        F: FnOnce(&mut Metadata, &[DbTable]) -> MetadataResult,
+    {
+        let mut pg_client = self.get_pg_client()?;
+        let db_tables = db::fetch_db_tables(&mut pg_client)?;
+
+        let mut new_metadata = self.metadata.clone();
+        update(&mut new_metadata, &db_tables)?;
+        new_metadata.set_db_columns(&db_tables);
+        new_metadata.resource_version = self.metadata.resource_version + 1;
+
+        let inconsistent_objects = new_metadata.inconsistent_objects(&db_tables);
+        let new_inconsistencies: Vec<String> = inconsistent_objects
+            .iter()
+            .filter(|object| !self.inconsistent_objects.contains(object))
+            .map(|object| object.reason.clone())
+            .collect();
+        if !allow_inconsistent_metadata && !new_inconsistencies.is_empty() {
+            return Err(GQLRSError::new(GQLRSErrorType::InconsistentMetadata(
+                new_inconsistencies.join(", "),
+            )));
+        }
+
+        if self.persist_metadata {
+            catalog::save_metadata(
+                &mut pg_client,
+                &new_metadata,
+                self.metadata.resource_version,
+            )?;
+        }
+
+        // NOTE: no instance listens for the changes to the tables that are no longer tracked
+        let new_tracked_tables = new_metadata.tracked_tables();
+        let untracked_tables: Vec<QualifiedTable> = self
+            .metadata
+            .tracked_tables()
+            .into_iter()
+            .filter(|table| !new_tracked_tables.contains(table))
+            .collect();
+        for table in untracked_tables.iter() {
+            if let Err(err) = db::remove_table_change_trigger(&mut pg_client, table) {
+                warn!("Failed to remove the change trigger on {}: {}", table, err);
+            }
+        }
+
+        self.metadata = new_metadata;
+        self.inconsistent_objects = inconsistent_objects;
+
+        Ok(())
     }
 
-    pub fn metadata_untrack_table(&mut self, table_info: QualifiedTable) -> MetadataResult {
-        self.metadata.untrack_table(table_info)
+    /// Rejects a request made against a resource version other than the current one
+    pub fn check_resource_version(&self, resource_version: Option<u64>) -> MetadataResult {
+        match resource_version {
+            Some(version) if version != self.metadata.resource_version => Err(GQLRSError::new(
+                GQLRSErrorType::ResourceVersionConflict(version),
+            )),
+            _ => Ok(()),
+        }
     }
 
     pub fn get_metadata(&self) -> &Metadata {
         &self.metadata
     }
-
-    pub fn replace_metadata(&mut self, new_md: &Metadata) {
-        self.metadata.set_metadata(new_md)
-    }
 }
 
 pub struct AppState(pub Mutex<ServerCtx>);
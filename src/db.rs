@@ -1,10 +1,27 @@
-use postgres::{Client, NoTls, Row};
+use indexmap::IndexMap;
+use log::warn;
+use postgres::types::Json;
+use postgres::{Client, NoTls, Row, Transaction};
 use r2d2::{Error, Pool};
 use r2d2_postgres::PostgresConnectionManager;
+use std::time::Duration;
 
+use crate::context::{PGPool, PermissionsMode};
 use crate::error;
-use crate::gql_types::{FieldInfo, FieldName, GQLArgTypeWithOrderBy, SUPPORTED_INT_GQL_ARGUMENTS};
-use crate::metadata::Metadata;
+use crate::gql_types::{
+    CursorOrdering, FieldInfo, FieldName, GQLArgType, GQLArgTypeWithOrderBy, MutationFieldInfo,
+    MutationOutputField, MutationRootFields, QueryVariableValues, QueryVariables, RootFields,
+    SESSION_VARIABLES_VARIABLE, STREAM_CURSOR_VARIABLE, SUPPORTED_INT_GQL_ARGUMENTS,
+    TYPENAME_FIELD,
+};
+use crate::metadata::{
+    DbTable, Metadata, MutationKind, NamingConvention, QualifiedTable, TableMetadata,
+};
+use crate::permissions::{
+    self, DeletePermission, InsertPermission, Permission, ResolvedSelectPermission,
+    SelectPermission, UpdatePermission, ADMIN_ROLE,
+};
+use crate::session::{SessionVariables, SESSION_VARIABLE_PREFIX};
 use crate::utils;
 
 pub fn get_pg_pool(
@@ -15,47 +32,223 @@ pub fn get_pg_pool(
     Pool::new(manager)
 }
 
+/// Adds the `LIMIT`/`OFFSET` clause to the query, where the value of the argument is
+/// capped at the `max_value` (like the `limit` of a select permission), if there's one
 #[inline]
 fn add_int_arg_to_query(
     query_str: &mut String,
     arg_name: &str,
     arg_value: Option<&GQLArgTypeWithOrderBy>,
-) {
-    match arg_value {
-        None => (),
-        Some(val) => {
-            query_str.push_str(format!("{} {} ", arg_name.to_uppercase(), val.get_num()).as_str());
+    max_value: Option<u64>,
+    variables: &QueryVariables,
+) -> Result<(), error::GQLRSError> {
+    let arg_sql = match (arg_value, max_value) {
+        (None, None) => return Ok(()),
+        (None, Some(max_value)) => max_value.to_string(),
+        (Some(val), max_value) => {
+            let val_sql = match val {
+                GQLArgType::Variable(variable_name) => variables.int_to_sql(variable_name)?,
+                _ => val.get_num().to_string(),
+            };
+            match max_value {
+                Some(max_value) => format!("LEAST({}, {})", val_sql, max_value),
+                None => val_sql,
+            }
         }
+    };
+
+    query_str.push_str(format!("{} {} ", arg_name.to_uppercase(), arg_sql).as_str());
+
+    Ok(())
+}
+
+/// Finds the tracked table that a root field fetches its rows from
+pub fn root_field_table<'a>(
+    root_field: &FieldName,
+    field_info: &FieldInfo,
+    current_metadata: &'a Metadata,
+) -> Option<&'a TableMetadata> {
+    match field_info.stream_cursor() {
+        Some(_) => current_metadata.check_for_stream_table_in_metadata(&root_field.name()),
+        None => current_metadata.check_for_table_in_metadata(&root_field.name()),
     }
 }
 
-/// This is a helper to construct the SQL query to fetch results from the database
-pub fn get_rows_gql_query(
-    client: &mut Client,
+fn find_root_field_table<'a>(
     root_field: &FieldName,
     field_info: &FieldInfo,
-    current_metadata: Metadata,
-) -> Result<Row, error::GQLRSError> {
+    current_metadata: &'a Metadata,
+) -> Result<&'a TableMetadata, error::GQLRSError> {
+    root_field_table(root_field, field_info, current_metadata).ok_or_else(|| {
+        error::GQLRSError::new(error::GQLRSErrorType::TableNotFoundInMetadata(
+            root_field.name(),
+        ))
+    })
+}
+
+/// The select permission of the role on the table, which is `None` for the admin role
+/// (that can select everything). An inherited role without a select permission of its
+/// own gets the union of the select permissions of its base roles. The tables that a role
+/// has no select permission on don't exist as far as the role is concerned
+fn find_select_permission(
+    root_field: &FieldName,
+    table: &TableMetadata,
+    current_metadata: &Metadata,
+    role: &str,
+) -> Result<Option<ResolvedSelectPermission>, error::GQLRSError> {
+    if role == ADMIN_ROLE {
+        return Ok(None);
+    }
+
+    let select_permission = match table.select_permission(role) {
+        Some(select_permission) => Some(ResolvedSelectPermission::from(select_permission.clone())),
+        None => current_metadata
+            .inherited_role(role)
+            .and_then(|inherited_role| {
+                let base_permissions: Vec<&SelectPermission> = inherited_role
+                    .role_set
+                    .iter()
+                    .filter_map(|base_role| table.select_permission(base_role))
+                    .collect();
+                permissions::union_select_permissions(&base_permissions)
+            }),
+    };
+
+    select_permission.map(Some).ok_or_else(|| {
+        error::GQLRSError::new(error::GQLRSErrorType::TableNotFoundInMetadata(
+            root_field.name(),
+        ))
+    })
+}
+
+/// Makes sure that the role is allowed to select the column that the field maps to
+fn check_column_permission(
+    select_permission: Option<&ResolvedSelectPermission>,
+    table: &TableMetadata,
+    naming_convention: NamingConvention,
+    field_name: &str,
+) -> Result<(), error::GQLRSError> {
+    let column_name = table.column_name(field_name, naming_convention)?;
+
+    match select_permission {
+        Some(select_permission) if !select_permission.permission.allows_column(&column_name) => {
+            Err(error::GQLRSError::new(
+                error::GQLRSErrorType::FieldNotFound(
+                    String::from(field_name),
+                    table.type_name(naming_convention),
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Builds the select list for the fields of the rows of a table, as far as the select
+/// permission (if any) lets the role select them
+fn select_list_sql(
+    fields: &[FieldName],
+    table: &TableMetadata,
+    select_permission: Option<&ResolvedSelectPermission>,
+    naming_convention: NamingConvention,
+    variables: &QueryVariables,
+) -> Result<String, error::GQLRSError> {
+    let mut select_list: Vec<String> = Vec::new();
+    for field_name in fields.iter() {
+        if field_name.name() == TYPENAME_FIELD {
+            select_list.push(field_name.to_sql(table, naming_convention)?);
+            continue;
+        }
+
+        check_column_permission(
+            select_permission,
+            table,
+            naming_convention,
+            &field_name.name(),
+        )?;
+
+        let column_name = table.column_name(&field_name.name(), naming_convention)?;
+        // NOTE: the columns that only some of the base roles of an inherited role can
+        // select are null on the rows that those base roles can't select
+        match select_permission.and_then(|permission| permission.column_filters.get(&column_name)) {
+            Some(column_filter) => select_list.push(format!(
+                "CASE WHEN {} THEN {} ELSE NULL END AS {}",
+                permissions::bool_exp_to_sql(column_filter, &|session_variable| {
+                    variables.session_variable_to_sql(session_variable)
+                })?,
+                utils::dquote(&column_name),
+                utils::dquote(&field_name.output_name())
+            )),
+            None => select_list.push(field_name.to_sql(table, naming_convention)?),
+        }
+    }
+
+    Ok(select_list.join(", "))
+}
+
+/// This is a helper to construct the SQL query that fetches the result of a single root field
+fn root_field_to_sql(
+    root_field: &FieldName,
+    field_info: &FieldInfo,
+    current_metadata: &Metadata,
+    variables: &QueryVariables,
+    role: &str,
+) -> Result<String, error::GQLRSError> {
+    let table = find_root_field_table(root_field, field_info, current_metadata)?;
+    let select_permission = find_select_permission(root_field, table, current_metadata, role)?;
+    let select_permission = select_permission.as_ref();
+    let naming_convention = current_metadata.naming_convention();
+    let select_list = select_list_sql(
+        field_info.fields(),
+        table,
+        select_permission,
+        naming_convention,
+        variables,
+    )?;
+
+    // NOTE: since we're using json_agg here, the DB has to be of v9 or over
+    Ok(format!(
+        "SELECT coalesce(json_agg(data), '[]') FROM ({}) as data",
+        root_field_select_sql(
+            &select_list,
+            table,
+            select_permission,
+            naming_convention,
+            field_info,
+            variables
+        )?
+    ))
+}
+
+/// Constructs the `SELECT` statement for the rows of a root field, with the given select list.
+/// The columns in the arguments are mapped back to the columns of the table in the database,
+/// and the rows are limited to the ones that the select permission (if any) allows
+fn root_field_select_sql(
+    select_list: &str,
+    table: &TableMetadata,
+    select_permission: Option<&ResolvedSelectPermission>,
+    naming_convention: NamingConvention,
+    field_info: &FieldInfo,
+    variables: &QueryVariables,
+) -> Result<String, error::GQLRSError> {
     let mut query = String::new();
     let query_has_args = !field_info.args().is_empty();
+    let column_sql = |graphql_name: &str| {
+        table
+            .column_name(graphql_name, naming_convention)
+            .map(|column_name| utils::dquote(&column_name))
+    };
 
     // ----- Query construction starts here -----
 
-    // NOTE: since we're using json_agg here, the DB has to be of v9 or over
-    query.push_str(
-        format!(
-            "SELECT coalesce(json_agg(data), '[]') AS {} FROM (SELECT ",
-            root_field.alias()
-        )
-        .as_str(),
-    );
+    query.push_str("SELECT ");
 
     // add the distinct on clause (if necessary)
     if query_has_args && field_info.args().contains_key("distinct_on") {
         let distinct_col = field_info.args().get("distinct_on");
         match distinct_col {
             Some(val) => {
-                query.push_str(format!("DISTINCT ON({}) ", val.get_string()).as_str());
+                query
+                    .push_str(format!("DISTINCT ON({}) ", column_sql(&val.get_string())?).as_str());
             }
             None => {
                 // NOTE: this case would be highly unlikely since we're checking whether
@@ -67,39 +260,57 @@ pub fn get_rows_gql_query(
         }
     }
 
-    for field_name in field_info.fields().iter() {
-        query.push_str(format!("{}, ", field_name.to_sql()).as_str());
+    query.push_str(select_list);
+
+    query.push_str(format!(" FROM {} ", table.table).as_str());
+
+    let mut conditions: Vec<String> = Vec::new();
+
+    if let Some(select_permission) = select_permission {
+        conditions.push(format!(
+            "({})",
+            permissions::bool_exp_to_sql(
+                &select_permission.permission.filter,
+                &|session_variable| { variables.session_variable_to_sql(session_variable) }
+            )?
+        ));
     }
 
-    // remove the extra (", ")
-    query.pop();
-    query.pop();
+    // NOTE: a streaming root field only fetches the rows that come after its cursor.
+    // `json_populate_record` is what casts the cursor's value to the column's type
+    let mut stream_order_by: Option<String> = None;
+    if let Some(stream_cursor) = field_info.stream_cursor() {
+        check_column_permission(
+            select_permission,
+            table,
+            naming_convention,
+            &stream_cursor.column,
+        )?;
+        let column_name = table.column_name(&stream_cursor.column, naming_convention)?;
+        let cursor_column = utils::dquote(&column_name);
+        conditions.push(format!(
+            "{} {} (json_populate_record(null::{}, json_build_object({}, {}->{}))).{}",
+            cursor_column,
+            stream_cursor.ordering.comparison_sql(),
+            table.table,
+            utils::squote(&column_name),
+            variables.json_to_sql(STREAM_CURSOR_VARIABLE)?,
+            utils::squote(&stream_cursor.column),
+            cursor_column,
+        ));
+        stream_order_by = Some(format!(
+            "ORDER BY {} {} ",
+            cursor_column,
+            stream_cursor.ordering.to_sql()
+        ));
+    }
 
-    let table_name = root_field.name();
-    match current_metadata.check_for_table_in_metadata(&table_name) {
-        Some(table) => {
-            query.push_str(format!(" FROM {} ", table.to_string()).as_str());
-        }
-        None => {
-            return Err(error::GQLRSError::new(
-                error::GQLRSErrorType::TableNotFoundInMetadata(root_field.name()),
-            ));
-        }
+    if !conditions.is_empty() {
+        query.push_str(format!("WHERE {} ", conditions.join(" AND ")).as_str());
     }
 
-    if query_has_args {
-        SUPPORTED_INT_GQL_ARGUMENTS.iter().for_each(|field_arg| {
-            let arg_val = field_info.args().get(*field_arg);
-            match *field_arg {
-                "limit" => {
-                    add_int_arg_to_query(&mut query, "limit", arg_val);
-                }
-                "offset" => {
-                    add_int_arg_to_query(&mut query, "offset", arg_val);
-                }
-                _ => (),
-            }
-        });
+    if let Some(stream_order_by) = stream_order_by {
+        query.push_str(&stream_order_by);
     }
 
     // See if there's a requirement of the `order by` clause
@@ -111,15 +322,15 @@ pub fn get_rows_gql_query(
                 let order_by_map = val.get_object();
 
                 for (col_name, order_by_clause) in order_by_map.iter() {
-                    let quoted_col_name = utils::dquote(col_name);
                     query.push_str(
-                        format!("{} {},", quoted_col_name, order_by_clause.to_sql()).as_str(),
+                        format!("{} {},", column_sql(col_name)?, order_by_clause.to_sql()).as_str(),
                     );
                 }
 
                 // NOTE: Popping the last character here for the hanging comma that
                 // might be present upon adding these statements to the query string
                 query.pop();
+                query.push(' ');
             }
             // NOTE: this is not plausible
             None => {
@@ -130,14 +341,942 @@ pub fn get_rows_gql_query(
         }
     }
 
-    query.push_str(") as data");
+    // NOTE: `LIMIT` and `OFFSET` have to follow the `ORDER BY` clause. The `limit` of
+    // the select permission applies even when the query has no `limit` of its own
+    for field_arg in SUPPORTED_INT_GQL_ARGUMENTS.iter() {
+        let arg_val = field_info.args().get(*field_arg);
+        match *field_arg {
+            "limit" => {
+                let max_limit = select_permission
+                    .and_then(|select_permission| select_permission.permission.limit);
+                add_int_arg_to_query(&mut query, "limit", arg_val, max_limit, variables)?;
+            }
+            "offset" => {
+                add_int_arg_to_query(&mut query, "offset", arg_val, None, variables)?;
+            }
+            _ => (),
+        }
+    }
 
     // ----- Query construction ends here -----
 
+    Ok(query)
+}
+
+/// Builds the SQL expression for the cursor of the last row in the batch of the streaming
+/// root field (if there's one), which is `NULL` when there are no new rows in the batch
+fn stream_cursor_to_sql(
+    root_fields: &RootFields,
+    current_metadata: &Metadata,
+    variables: &QueryVariables,
+    role: &str,
+) -> Result<String, error::GQLRSError> {
+    for (root_field, field_info) in root_fields.iter() {
+        if let Some(stream_cursor) = field_info.stream_cursor() {
+            let table = find_root_field_table(root_field, field_info, current_metadata)?;
+            let select_permission =
+                find_select_permission(root_field, table, current_metadata, role)?;
+            let naming_convention = current_metadata.naming_convention();
+            let cursor_column =
+                utils::dquote(&table.column_name(&stream_cursor.column, naming_convention)?);
+            let last_row_ordering = match stream_cursor.ordering {
+                CursorOrdering::Asc => CursorOrdering::Desc,
+                CursorOrdering::Desc => CursorOrdering::Asc,
+            };
+
+            // NOTE: the cursor is keyed by the GraphQL name of the column, like the
+            // `initial_value` of the cursor that it replaces
+            return Ok(format!(
+                "(SELECT json_build_object({}, batch.{}) FROM ({}) AS batch ORDER BY batch.{} {} LIMIT 1)",
+                utils::squote(&stream_cursor.column),
+                cursor_column,
+                root_field_select_sql(
+                    &cursor_column,
+                    table,
+                    select_permission.as_ref(),
+                    naming_convention,
+                    field_info,
+                    variables
+                )?,
+                cursor_column,
+                last_row_ordering.to_sql()
+            ));
+        }
+    }
+
+    Ok(String::from("NULL::json"))
+}
+
+/// Builds a JSON object expression out of the names of its keys and the (JSON) SQL
+/// expressions of their values, which are kept in the order that they're given in
+fn json_object_sql(alias: &str, entries: &[(String, String)]) -> String {
+    // NOTE: Postgres caps the number of arguments to a function at 100, so rather than
+    // passing every entry to `json_build_object`, the entries are rows of their own that
+    // are aggregated into the object. Ordering the aggregate by the position of the entry
+    // keeps the keys in order, which `json_object_agg` (unlike `jsonb_object_agg`) retains
+    let rows: Vec<String> = entries
+        .iter()
+        .enumerate()
+        .map(|(position, (name, value_sql))| {
+            format!("({}, {}, ({}))", position, utils::squote(name), value_sql)
+        })
+        .collect();
+    let alias = utils::dquote(alias);
+
+    format!(
+        "(SELECT json_object_agg({alias}.\"name\", {alias}.\"result\" ORDER BY {alias}.\"position\") \
+        FROM (VALUES {rows}) AS {alias}(\"position\", \"name\", \"result\"))",
+        alias = alias,
+        rows = rows.join(", ")
+    )
+}
+
+/// Builds a JSON object expression that holds the results of all the root fields
+fn root_fields_to_sql(
+    root_fields: &RootFields,
+    current_metadata: &Metadata,
+    variables: &QueryVariables,
+    role: &str,
+) -> Result<String, error::GQLRSError> {
+    let mut root_field_results: Vec<(String, String)> = Vec::new();
+
+    for (root_field, field_info) in root_fields.iter() {
+        let root_field_query =
+            root_field_to_sql(root_field, field_info, current_metadata, variables, role)?;
+        root_field_results.push((root_field.output_name(), root_field_query));
+    }
+
+    Ok(json_object_sql("_root_fields", &root_field_results))
+}
+
+// NOTE: the rows that a mutation writes are kept in a CTE by this name, which is what the
+// response of the mutation and the check of the permission are built from
+const MUTATED_ROWS: &str = "_mutated";
+
+fn invalid_mutation_input(message: String) -> error::GQLRSError {
+    error::GQLRSError::new(error::GQLRSErrorType::InvalidInput(message))
+}
+
+/// The permission of the kind that the role writes the table with, which is `None` for
+/// the admin role. The tables that a role has no such permission on don't have the
+/// mutation root field as far as the role is concerned.
+/// Inherited roles only combine the select permissions of their base roles, so rather than
+/// guessing how their insert, update and delete permissions would combine, they can't write
+fn find_mutation_permission<'a, P: Permission>(
+    root_field: &FieldName,
+    table: &'a TableMetadata,
+    current_metadata: &Metadata,
+    role: &str,
+) -> Result<Option<&'a P>, error::GQLRSError> {
+    if role == ADMIN_ROLE {
+        return Ok(None);
+    }
+    if current_metadata.inherited_role(role).is_some() {
+        return Err(error::GQLRSError::new(error::GQLRSErrorType::AccessDenied(
+            format!("the inherited role `{}` can't run mutations", role),
+        )));
+    }
+
+    table.permission::<P>(role).map(Some).ok_or_else(|| {
+        error::GQLRSError::new(error::GQLRSErrorType::TableNotFoundInMetadata(
+            root_field.name(),
+        ))
+    })
+}
+
+// NOTE: the values that the clients write are left as untyped literals too, so that
+// Postgres reads them as values of the type of the column that they're written to
+fn input_value_to_sql(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::from("NULL"),
+        serde_json::Value::String(text) => utils::squote(text),
+        other => utils::squote(&other.to_string()),
+    }
+}
+
+/// The name of the column in the database that a field of a mutation argument refers to,
+/// which has to be one of the allowed columns (if the role is restricted to any)
+fn input_column_name(
+    field_name: &str,
+    table: &TableMetadata,
+    naming_convention: NamingConvention,
+    allowed_columns: Option<&[String]>,
+) -> Result<String, error::GQLRSError> {
+    let column_name = table.column_name(field_name, naming_convention)?;
+    match allowed_columns {
+        Some(allowed_columns) if !allowed_columns.contains(&column_name) => Err(
+            error::GQLRSError::new(error::GQLRSErrorType::FieldNotFound(
+                String::from(field_name),
+                table.type_name(naming_convention),
+            )),
+        ),
+        _ => Ok(column_name),
+    }
+}
+
+/// The SQL values of the columns that an object (of `objects` or `_set`) writes, by the
+/// names of the columns in the database. The role can only write the columns that its
+/// permission allows, and the presets of the permission win over the values of the object
+fn input_columns_sql(
+    object: &serde_json::Value,
+    table: &TableMetadata,
+    naming_convention: NamingConvention,
+    allowed_columns: Option<&[String]>,
+    presets: Option<&IndexMap<String, serde_json::Value>>,
+    variables: &QueryVariables,
+) -> Result<IndexMap<String, String>, error::GQLRSError> {
+    let fields = object.as_object().ok_or_else(|| {
+        invalid_mutation_input(format!("Expected an object of columns, found {}", object))
+    })?;
+
+    let mut columns: IndexMap<String, String> = IndexMap::new();
+    for (field_name, value) in fields.iter() {
+        columns.insert(
+            input_column_name(field_name, table, naming_convention, allowed_columns)?,
+            input_value_to_sql(value),
+        );
+    }
+
+    for (column_name, value) in presets.into_iter().flatten() {
+        columns.insert(
+            column_name.clone(),
+            permissions::value_to_sql(value, &|session_variable| {
+                variables.session_variable_to_sql(session_variable)
+            })?,
+        );
+    }
+
+    Ok(columns)
+}
+
+/// Maps the fields in the `where` argument of a mutation to the columns in the database,
+/// so that it's a boolean expression like the ones of the permissions. Like with the
+/// objects that are written, the fields have to be among the allowed columns (if any)
+fn where_to_bool_exp(
+    bool_exp: &serde_json::Value,
+    table: &TableMetadata,
+    naming_convention: NamingConvention,
+    allowed_columns: Option<&[String]>,
+) -> Result<serde_json::Value, error::GQLRSError> {
+    // NOTE: anything that isn't an object is reported by `bool_exp_to_sql`
+    let fields = match bool_exp.as_object() {
+        Some(fields) => fields,
+        None => return Ok(bool_exp.clone()),
+    };
+
+    let mut mapped_fields = serde_json::Map::new();
+    for (key, value) in fields.iter() {
+        match key.as_str() {
+            "_and" | "_or" => {
+                let bool_exps = match value.as_array() {
+                    Some(bool_exps) => serde_json::Value::Array(
+                        bool_exps
+                            .iter()
+                            .map(|bool_exp| {
+                                where_to_bool_exp(
+                                    bool_exp,
+                                    table,
+                                    naming_convention,
+                                    allowed_columns,
+                                )
+                            })
+                            .collect::<Result<Vec<serde_json::Value>, error::GQLRSError>>()?,
+                    ),
+                    None => value.clone(),
+                };
+                mapped_fields.insert(key.clone(), bool_exps);
+            }
+            "_not" => {
+                mapped_fields.insert(
+                    key.clone(),
+                    where_to_bool_exp(value, table, naming_convention, allowed_columns)?,
+                );
+            }
+            field_name => {
+                mapped_fields.insert(
+                    input_column_name(field_name, table, naming_convention, allowed_columns)?,
+                    value.clone(),
+                );
+            }
+        }
+    }
+
+    Ok(serde_json::Value::Object(mapped_fields))
+}
+
+/// The condition on the rows that an update or a delete writes, which are the rows that
+/// both the `where` argument and the filter of the permission (if any) pick. Since the
+/// `where` argument selects rows, a role other than the admin role can only refer to the
+/// columns (and pick the rows) that its select permission allows, i.e. none without one
+fn mutation_filter_sql(
+    field_info: &MutationFieldInfo,
+    table: &TableMetadata,
+    naming_convention: NamingConvention,
+    permission_filter: Option<&serde_json::Value>,
+    variables: &QueryVariables,
+    role: &str,
+) -> Result<String, error::GQLRSError> {
+    let where_arg = field_info
+        .argument("where")
+        .ok_or_else(|| invalid_mutation_input(String::from("The `where` argument is required")))?;
+
+    let select_permission = match role {
+        ADMIN_ROLE => None,
+        _ => Some(table.select_permission(role)),
+    };
+    let allowed_columns = select_permission.map(|select_permission| {
+        select_permission.map_or(&[][..], |select_permission| {
+            select_permission.columns.as_slice()
+        })
+    });
+
+    // NOTE: the values in the `where` argument are always taken at their word, unlike
+    // the values of the permissions that can be session variables
+    let mut conditions = vec![format!(
+        "({})",
+        permissions::bool_exp_to_sql(
+            &where_to_bool_exp(where_arg, table, naming_convention, allowed_columns)?,
+            &|value| Ok(utils::squote(value))
+        )?
+    )];
+    let select_filter = select_permission
+        .flatten()
+        .map(|select_permission| &select_permission.filter);
+    for filter in permission_filter.into_iter().chain(select_filter) {
+        conditions.push(format!(
+            "({})",
+            permissions::bool_exp_to_sql(filter, &|session_variable| {
+                variables.session_variable_to_sql(session_variable)
+            })?
+        ));
+    }
+
+    Ok(conditions.join(" AND "))
+}
+
+fn insert_to_sql(
+    field_info: &MutationFieldInfo,
+    table: &TableMetadata,
+    naming_convention: NamingConvention,
+    permission: Option<&InsertPermission>,
+    variables: &QueryVariables,
+) -> Result<String, error::GQLRSError> {
+    // NOTE: a single object is as good as a list of just the one object
+    let objects = match field_info.argument("objects") {
+        Some(serde_json::Value::Array(objects)) => objects.clone(),
+        Some(object @ serde_json::Value::Object(_)) => vec![object.clone()],
+        _ => {
+            return Err(invalid_mutation_input(String::from(
+                "The `objects` argument should be a list of objects",
+            )))
+        }
+    };
+
+    let rows = objects
+        .iter()
+        .map(|object| {
+            input_columns_sql(
+                object,
+                table,
+                naming_convention,
+                permission.map(|permission| permission.columns.as_slice()),
+                permission.map(|permission| &permission.set),
+                variables,
+            )
+        })
+        .collect::<Result<Vec<IndexMap<String, String>>, error::GQLRSError>>()?;
+
+    if rows.is_empty() {
+        return Ok(format!("SELECT * FROM {} WHERE false", table.table));
+    }
+
+    let mut columns: Vec<&String> = Vec::new();
+    for row in rows.iter() {
+        for column in row.keys() {
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+    }
+    if columns.is_empty() {
+        return Err(invalid_mutation_input(String::from(
+            "The objects to insert should have at least one column",
+        )));
+    }
+
+    // NOTE: the columns that a row leaves out get their default values
+    let values: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let row_values: Vec<&str> = columns
+                .iter()
+                .map(|column| row.get(*column).map_or("DEFAULT", String::as_str))
+                .collect();
+            format!("({})", row_values.join(", "))
+        })
+        .collect();
+
+    Ok(format!(
+        "INSERT INTO {} ({}) VALUES {} RETURNING *",
+        table.table,
+        columns
+            .iter()
+            .map(|column| utils::dquote(column))
+            .collect::<Vec<String>>()
+            .join(", "),
+        values.join(", ")
+    ))
+}
+
+fn update_to_sql(
+    field_info: &MutationFieldInfo,
+    table: &TableMetadata,
+    naming_convention: NamingConvention,
+    permission: Option<&UpdatePermission>,
+    variables: &QueryVariables,
+    role: &str,
+) -> Result<String, error::GQLRSError> {
+    let set_columns = input_columns_sql(
+        field_info
+            .argument("_set")
+            .unwrap_or(&serde_json::Value::Object(serde_json::Map::new())),
+        table,
+        naming_convention,
+        permission.map(|permission| permission.columns.as_slice()),
+        permission.map(|permission| &permission.set),
+        variables,
+    )?;
+    if set_columns.is_empty() {
+        return Err(invalid_mutation_input(String::from(
+            "The `_set` argument should have at least one column",
+        )));
+    }
+
+    Ok(format!(
+        "UPDATE {} SET {} WHERE {} RETURNING *",
+        table.table,
+        set_columns
+            .iter()
+            .map(|(column, value)| format!("{} = {}", utils::dquote(column), value))
+            .collect::<Vec<String>>()
+            .join(", "),
+        mutation_filter_sql(
+            field_info,
+            table,
+            naming_convention,
+            permission.map(|permission| &permission.filter),
+            variables,
+            role
+        )?
+    ))
+}
+
+fn delete_to_sql(
+    field_info: &MutationFieldInfo,
+    table: &TableMetadata,
+    naming_convention: NamingConvention,
+    permission: Option<&DeletePermission>,
+    variables: &QueryVariables,
+    role: &str,
+) -> Result<String, error::GQLRSError> {
+    Ok(format!(
+        "DELETE FROM {} WHERE {} RETURNING *",
+        table.table,
+        mutation_filter_sql(
+            field_info,
+            table,
+            naming_convention,
+            permission.map(|permission| &permission.filter),
+            variables,
+            role
+        )?
+    ))
+}
+
+/// Builds the response of a mutation root field out of the rows that it wrote. The
+/// `returning` rows are the ones that the role can select, as per its select permission
+fn mutation_response_sql(
+    root_field: &FieldName,
+    field_info: &MutationFieldInfo,
+    table: &TableMetadata,
+    current_metadata: &Metadata,
+    variables: &QueryVariables,
+    role: &str,
+) -> Result<String, error::GQLRSError> {
+    let naming_convention = current_metadata.naming_convention();
+    let mutated_rows = utils::dquote(MUTATED_ROWS);
+    let mut output_fields: Vec<(String, String)> = Vec::new();
+
+    for output_field in field_info.output_fields().iter() {
+        match output_field {
+            MutationOutputField::AffectedRows(field_name) => output_fields.push((
+                field_name.output_name(),
+                format!("SELECT to_json(count(*)) FROM {}", mutated_rows),
+            )),
+            MutationOutputField::Typename(field_name) => output_fields.push((
+                field_name.output_name(),
+                format!(
+                    "SELECT to_json({}::text)",
+                    utils::squote(&table.mutation_response_type_name(naming_convention))
+                ),
+            )),
+            MutationOutputField::Returning(field_name, fields) => {
+                let select_permission =
+                    find_select_permission(root_field, table, current_metadata, role).map_err(
+                        |_| {
+                            error::GQLRSError::new(error::GQLRSErrorType::FieldNotFound(
+                                field_name.name(),
+                                table.mutation_response_type_name(naming_convention),
+                            ))
+                        },
+                    )?;
+                let select_permission = select_permission.as_ref();
+                let filter = match select_permission {
+                    Some(select_permission) => permissions::bool_exp_to_sql(
+                        &select_permission.permission.filter,
+                        &|session_variable| variables.session_variable_to_sql(session_variable),
+                    )?,
+                    None => String::from("true"),
+                };
+                output_fields.push((
+                    field_name.output_name(),
+                    format!(
+                        "SELECT coalesce(json_agg(data), '[]') FROM (SELECT {} FROM {} WHERE {}) AS data",
+                        select_list_sql(
+                            fields,
+                            table,
+                            select_permission,
+                            naming_convention,
+                            variables
+                        )?,
+                        mutated_rows,
+                        filter
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok(json_object_sql("_fields", &output_fields))
+}
+
+/// Builds the statement of a mutation root field, which writes the rows in a CTE and
+/// selects the response of the root field as `data`, along with whether every row that
+/// was written passes the `check` of the permission, as `check`
+fn mutation_root_field_to_sql(
+    root_field: &FieldName,
+    field_info: &MutationFieldInfo,
+    current_metadata: &Metadata,
+    variables: &QueryVariables,
+    role: &str,
+) -> Result<(MutationKind, String), error::GQLRSError> {
+    let (table, mutation_kind) = current_metadata
+        .check_for_mutation_table_in_metadata(&root_field.name())
+        .ok_or_else(|| {
+            error::GQLRSError::new(error::GQLRSErrorType::TableNotFoundInMetadata(
+                root_field.name(),
+            ))
+        })?;
+    let naming_convention = current_metadata.naming_convention();
+
+    let (mutation_sql, check) = match mutation_kind {
+        MutationKind::Insert => {
+            let permission = find_mutation_permission::<InsertPermission>(
+                root_field,
+                table,
+                current_metadata,
+                role,
+            )?;
+            (
+                insert_to_sql(field_info, table, naming_convention, permission, variables)?,
+                permission.map(|permission| &permission.check),
+            )
+        }
+        MutationKind::Update => {
+            let permission = find_mutation_permission::<UpdatePermission>(
+                root_field,
+                table,
+                current_metadata,
+                role,
+            )?;
+            (
+                update_to_sql(
+                    field_info,
+                    table,
+                    naming_convention,
+                    permission,
+                    variables,
+                    role,
+                )?,
+                permission.map(|permission| &permission.check),
+            )
+        }
+        MutationKind::Delete => {
+            let permission = find_mutation_permission::<DeletePermission>(
+                root_field,
+                table,
+                current_metadata,
+                role,
+            )?;
+            (
+                delete_to_sql(
+                    field_info,
+                    table,
+                    naming_convention,
+                    permission,
+                    variables,
+                    role,
+                )?,
+                None,
+            )
+        }
+    };
+
+    let check_sql = match check {
+        Some(check) => permissions::bool_exp_to_sql(check, &|session_variable| {
+            variables.session_variable_to_sql(session_variable)
+        })?,
+        None => String::from("true"),
+    };
+
+    Ok((
+        mutation_kind,
+        format!(
+            "WITH {mutated_rows} AS ({mutation}) SELECT {response} AS \"data\", \
+            (SELECT coalesce(bool_and({check}), true) FROM {mutated_rows}) AS \"check\"",
+            mutated_rows = utils::dquote(MUTATED_ROWS),
+            mutation = mutation_sql,
+            response = mutation_response_sql(
+                root_field,
+                field_info,
+                table,
+                current_metadata,
+                variables,
+                role
+            )?,
+            check = check_sql
+        ),
+    ))
+}
+
+/// The role that the permissions in the metadata are applied for. In the RLS mode, the
+/// engine leaves the permissions to the database, and so builds the SQL as the admin role
+fn metadata_role(session_variables: &SessionVariables, permissions_mode: PermissionsMode) -> &str {
+    match permissions_mode {
+        PermissionsMode::Engine => session_variables.role(),
+        PermissionsMode::Rls => ADMIN_ROLE,
+    }
+}
+
+/// The setting that a session variable is available as in the RLS mode, e.g.
+/// `x-hasura-user-id` is `hasura.session.user_id`
+fn session_variable_setting(session_variable: &str) -> String {
+    let name: String = session_variable
+        .trim_start_matches(SESSION_VARIABLE_PREFIX)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("hasura.session.{}", name)
+}
+
+/// Makes the session available to the database for the rest of the transaction, as the
+/// `hasura.user` setting, so that triggers (and functions) can tell who it is that's running
+/// the query. In the RLS mode, every session variable is a setting of its own as well, and
+/// the transaction switches to the database role of the same name as the role of the session
+/// (unless it's the admin role), so that the row level security policies apply.
+/// Since all of these are local to the transaction, the pooled connection is left as it was
+fn set_transaction_session(
+    transaction: &mut Transaction,
+    session_variables: &SessionVariables,
+    permissions_mode: PermissionsMode,
+) -> Result<(), error::GQLRSError> {
+    transaction
+        .execute(
+            "SELECT set_config('hasura.user', $1, true)",
+            &[&session_variables.to_json().to_string()],
+        )
+        .map_err(db_error)?;
+
+    if permissions_mode == PermissionsMode::Rls {
+        let (settings, values): (Vec<String>, Vec<String>) = session_variables
+            .iter()
+            .map(|(name, value)| (session_variable_setting(name), value.clone()))
+            .unzip();
+        transaction
+            .execute(
+                "SELECT set_config(setting, value, true) FROM unnest($1::text[], $2::text[]) AS settings(setting, value)",
+                &[&settings, &values],
+            )
+            .map_err(db_error)?;
+
+        let role = session_variables.role();
+        if role != ADMIN_ROLE {
+            transaction
+                .batch_execute(&format!("SET LOCAL ROLE {}", utils::dquote(role)))
+                .map_err(db_error)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fetches the results of all the root fields of a query with a single SQL statement.
+/// Since it's just the one statement, every root field observes the same snapshot of
+/// the database and we only make a single round trip for the whole query.
+/// The rows and columns are limited to the ones that the select permissions of the role
+/// of the session allow, or in the RLS mode, to the ones that the database allows.
+pub fn get_rows_gql_query(
+    client: &mut Client,
+    root_fields: &RootFields,
+    current_metadata: &Metadata,
+    variables: &QueryVariableValues,
+    session_variables: &SessionVariables,
+    permissions_mode: PermissionsMode,
+) -> Result<Row, error::GQLRSError> {
+    let mut variables = variables.clone();
+    variables.insert(
+        String::from(SESSION_VARIABLES_VARIABLE),
+        session_variables.to_json(),
+    );
+
+    let query = format!(
+        "SELECT {} AS data",
+        root_fields_to_sql(
+            root_fields,
+            current_metadata,
+            &QueryVariables::Values(&variables),
+            metadata_role(session_variables, permissions_mode)
+        )?
+    );
+
     // ----- Run Query
 
-    let query_result = client.query_one(query.as_str(), &[]);
+    let mut transaction = client.transaction().map_err(db_error)?;
+    set_transaction_session(&mut transaction, session_variables, permissions_mode)?;
+    let row = transaction
+        .query_one(query.as_str(), &[])
+        .map_err(db_error)?;
+    transaction.commit().map_err(db_error)?;
+
+    Ok(row)
+}
+
+/// Runs the root fields of a mutation one after the other, in a single transaction, so
+/// that every root field sees the changes of the ones before it. The role can only write
+/// the rows (and columns) that its insert, update and delete permissions allow, and the
+/// transaction is rolled back if any of the rows that it writes fails the `check` of the
+/// permission. In the RLS mode, it's the policies of the database that decide instead
+pub fn run_gql_mutation(
+    client: &mut Client,
+    root_fields: &MutationRootFields,
+    current_metadata: &Metadata,
+    session_variables: &SessionVariables,
+    permissions_mode: PermissionsMode,
+) -> Result<IndexMap<String, serde_json::Value>, error::GQLRSError> {
+    let mut variables: QueryVariableValues = IndexMap::new();
+    variables.insert(
+        String::from(SESSION_VARIABLES_VARIABLE),
+        session_variables.to_json(),
+    );
+    let variables = QueryVariables::Values(&variables);
+    let role = metadata_role(session_variables, permissions_mode);
+
+    let mut transaction = client.transaction().map_err(db_error)?;
+    set_transaction_session(&mut transaction, session_variables, permissions_mode)?;
+
+    let mut results: IndexMap<String, serde_json::Value> = IndexMap::new();
+    for (root_field, field_info) in root_fields.iter() {
+        let (mutation_kind, query) =
+            mutation_root_field_to_sql(root_field, field_info, current_metadata, &variables, role)?;
+        let row = transaction
+            .query_one(query.as_str(), &[])
+            .map_err(db_error)?;
 
-    query_result
-        .map_err(|err| error::GQLRSError::new(error::GQLRSErrorType::DBError(format!("{:?}", err))))
+        // NOTE: returning early drops the transaction, which rolls it back
+        let check: bool = row.try_get("check").map_err(db_error)?;
+        if !check {
+            return Err(error::GQLRSError::new(
+                error::GQLRSErrorType::PermissionCheckFailed(String::from(mutation_kind.name())),
+            ));
+        }
+
+        let data: Json<serde_json::Value> = row.try_get("data").map_err(db_error)?;
+        results.insert(root_field.output_name(), data.0);
+    }
+
+    transaction.commit().map_err(db_error)?;
+
+    Ok(results)
+}
+
+/// Fetches the results of a query for many sets of variables with a single SQL statement.
+/// The sets of variables are unnested into rows, and the query is run against each of them
+/// with a lateral join. Every row of the result has the `subscriber_id` and its `data`,
+/// along with the new `cursor` for streaming subscriptions.
+/// The subscribers share the role of the session, and in the RLS mode, the whole session.
+pub fn get_multiplexed_rows_gql_query(
+    client: &mut Client,
+    root_fields: &RootFields,
+    current_metadata: &Metadata,
+    subscriber_ids: &[i64],
+    subscriber_variables: &[serde_json::Value],
+    session_variables: &SessionVariables,
+    permissions_mode: PermissionsMode,
+) -> Result<Vec<Row>, error::GQLRSError> {
+    let variables = QueryVariables::Column("\"_subs\".\"variables\"");
+    let role = metadata_role(session_variables, permissions_mode);
+    let query = format!(
+        "SELECT \"_subs\".\"subscriber_id\", \"_fld_resp\".\"data\", \"_fld_resp\".\"cursor\" \
+        FROM unnest($1::bigint[], $2::json[]) AS \"_subs\"(\"subscriber_id\", \"variables\") \
+        LEFT OUTER JOIN LATERAL (SELECT {} AS \"data\", {} AS \"cursor\") AS \"_fld_resp\" ON true",
+        root_fields_to_sql(root_fields, current_metadata, &variables, role)?,
+        stream_cursor_to_sql(root_fields, current_metadata, &variables, role)?
+    );
+
+    // NOTE: the session of the transaction is only set in the RLS mode, since that's when
+    // the subscribers share all of it (and not just the role)
+    let mut transaction = client.transaction().map_err(db_error)?;
+    if permissions_mode == PermissionsMode::Rls {
+        set_transaction_session(&mut transaction, session_variables, permissions_mode)?;
+    }
+    let rows = transaction
+        .query(query.as_str(), &[&subscriber_ids, &subscriber_variables])
+        .map_err(db_error)?;
+    transaction.commit().map_err(db_error)?;
+
+    Ok(rows)
+}
+
+/// Fetches all the tables (and views) in the database along with their columns, except
+/// for the ones that belong to Postgres itself or to the catalog
+pub fn fetch_db_tables(client: &mut Client) -> Result<Vec<DbTable>, error::GQLRSError> {
+    let rows = client
+        .query(
+            "SELECT n.nspname AS table_schema, c.relname AS table_name, \
+            coalesce(array_agg(a.attname::text ORDER BY a.attnum) FILTER (WHERE a.attname IS NOT NULL), '{}') AS columns \
+            FROM pg_catalog.pg_class c \
+            JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace \
+            LEFT JOIN pg_catalog.pg_attribute a ON a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped \
+            WHERE c.relkind IN ('r', 'v', 'm', 'f', 'p') \
+            AND n.nspname NOT IN ('pg_catalog', 'information_schema', 'hdb_catalog') \
+            AND n.nspname NOT LIKE 'pg_toast%' \
+            GROUP BY n.nspname, c.relname \
+            ORDER BY n.nspname, c.relname",
+            &[],
+        )
+        .map_err(db_error)?;
+
+    Ok(rows
+        .iter()
+        .map(|row| DbTable {
+            table: QualifiedTable::new(row.get("table_schema"), row.get("table_name")),
+            columns: row.get("columns"),
+        })
+        .collect())
+}
+
+/// The channel on which the triggers of the tracked tables notify about changes
+pub const TABLE_CHANGE_CHANNEL: &str = "hdb_table_change";
+
+const TABLE_CHANGE_TRIGGER: &str = "hdb_notify_table_change";
+
+fn db_error(err: postgres::Error) -> error::GQLRSError {
+    error::GQLRSError::new(error::GQLRSErrorType::DBError(format!("{:?}", err)))
+}
+
+/// Creates the function used by the triggers that notify about changes to the tracked tables.
+/// The function lives in the `hdb_catalog` schema, so the catalog has to be initialised first
+pub fn create_table_change_notify_function(client: &mut Client) -> Result<(), error::GQLRSError> {
+    // NOTE: the payload of the notification is the table that was changed, in the
+    // same shape as the `QualifiedTable`s in the metadata
+    let query = format!(
+        "CREATE OR REPLACE FUNCTION hdb_catalog.{}() RETURNS trigger AS $$ \
+        BEGIN \
+            PERFORM pg_notify({}, json_build_object('schema', TG_TABLE_SCHEMA, 'table', TG_TABLE_NAME)::text); \
+            RETURN NULL; \
+        END; \
+        $$ LANGUAGE plpgsql;",
+        TABLE_CHANGE_TRIGGER,
+        utils::squote(TABLE_CHANGE_CHANNEL)
+    );
+
+    client.batch_execute(query.as_str()).map_err(db_error)
+}
+
+/// Installs a trigger on the table that notifies `TABLE_CHANGE_CHANNEL` of every change made to it,
+/// unless the table has it already, e.g. from another instance that's listening for the changes
+pub fn install_table_change_trigger(
+    client: &mut Client,
+    table: &QualifiedTable,
+) -> Result<(), error::GQLRSError> {
+    // NOTE: a statement level trigger fires just once for all the rows that a
+    // statement changes, and is the only kind that fires on a `TRUNCATE`.
+    // The trigger is only ever created when it's missing, since replacing it would
+    // lock the table and could miss the changes made in the meantime, and another
+    // instance creating it at the same time is fine too
+    let query = format!(
+        "DO $$ BEGIN \
+            IF NOT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = {trigger_name} AND tgrelid = {table_name}::regclass) THEN \
+                CREATE TRIGGER {trigger} AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON {table} \
+                FOR EACH STATEMENT EXECUTE PROCEDURE hdb_catalog.{trigger}(); \
+            END IF; \
+        EXCEPTION WHEN duplicate_object THEN NULL; \
+        END $$;",
+        trigger = TABLE_CHANGE_TRIGGER,
+        trigger_name = utils::squote(TABLE_CHANGE_TRIGGER),
+        table = table,
+        table_name = utils::squote(&table.to_string())
+    );
+
+    client.batch_execute(query.as_str()).map_err(db_error)
+}
+
+/// Removes the change trigger from a table that is no longer tracked. Tables without
+/// the trigger are left alone, since dropping a trigger locks the table
+pub fn remove_table_change_trigger(
+    client: &mut Client,
+    table: &QualifiedTable,
+) -> Result<(), error::GQLRSError> {
+    let query = format!(
+        "DO $$ BEGIN \
+            IF EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = {trigger_name} AND tgrelid = to_regclass({table_name})) THEN \
+                DROP TRIGGER IF EXISTS {trigger} ON {table}; \
+            END IF; \
+        END $$;",
+        trigger = TABLE_CHANGE_TRIGGER,
+        trigger_name = utils::squote(TABLE_CHANGE_TRIGGER),
+        table = table,
+        table_name = utils::squote(&table.to_string())
+    );
+
+    client.batch_execute(query.as_str()).map_err(db_error)
+}
+
+/// Listens for the notifications on a channel, on a thread of its own. Once the connection
+/// is listening, it's handed to `on_listen`, which is to wait on the notifications for as
+/// long as the connection works. Since the connection is held on to all the while, it's
+/// never returned to the pool. When it breaks, a new connection listens in its place after
+/// the retry interval, and so `on_listen` has to catch up on what it may have missed
+pub fn listen_on<F>(
+    pg_pool: PGPool,
+    channel: &'static str,
+    retry_interval: Duration,
+    mut on_listen: F,
+) where
+    F: FnMut(&mut Client) + Send + 'static,
+{
+    let listen_query = format!("LISTEN {};", channel);
+    std::thread::spawn(move || loop {
+        match pg_pool.get() {
+            Ok(mut listener) => match listener.batch_execute(listen_query.as_str()) {
+                Ok(()) => on_listen(&mut listener),
+                Err(err) => warn!("Failed to listen on the `{}` channel: {}", channel, err),
+            },
+            Err(err) => warn!(
+                "Failed to get a connection to listen on the `{}` channel: {}",
+                channel, err
+            ),
+        }
+        std::thread::sleep(retry_interval);
+    });
 }
@@ -13,6 +13,32 @@ pub enum GQLRSErrorType {
     DBError(String),
     #[error("ERROR: Invalid input supplied. `{0}`")]
     InvalidInput(String),
+    #[error("ERROR: Invalid request headers. `{0}`")]
+    InvalidHeaders(String),
+    #[error("ERROR: The metadata has been modified since resource version {0}")]
+    ResourceVersionConflict(u64),
+    #[error("ERROR: Both {1} and {2} would have the GraphQL name `{0}`")]
+    GraphQLNameConflict(String, String, String),
+    #[error("ERROR: The metadata is inconsistent with the database. {0}")]
+    InconsistentMetadata(String),
+    #[error("ERROR: The role `{0}` already has a {1} permission on {2}")]
+    PermissionAlreadyExists(String, String, String),
+    #[error("ERROR: The role `{0}` has no {1} permission on {2}")]
+    PermissionNotFound(String, String, String),
+    #[error("ERROR: The inherited role `{0}` already exists")]
+    InheritedRoleAlreadyExists(String),
+    #[error("ERROR: The inherited role `{0}` doesn't exist")]
+    InheritedRoleNotFound(String),
+    #[error("ERROR: field `{0}` not found in type: `{1}`")]
+    FieldNotFound(String, String),
+    #[error("ERROR: check constraint of an {0} permission has failed")]
+    PermissionCheckFailed(String),
+    #[error("ERROR: Access denied, {0}")]
+    AccessDenied(String),
+    #[error("ERROR: Could not verify JWT: {0}")]
+    InvalidJwt(String),
+    #[error("ERROR: The authentication hook failed, {0}")]
+    AuthHookError(String),
 }
 
 #[derive(Error, Debug, Serialize, Clone)]
@@ -25,4 +51,40 @@ impl GQLRSError {
     pub fn new(kind: GQLRSErrorType) -> GQLRSError {
         GQLRSError { kind }
     }
+
+    /// The code of the error in the `extensions` of a GraphQL error
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            GQLRSErrorType::InvalidJwt(_) => "invalid-jwt",
+            GQLRSErrorType::AccessDenied(_) => "access-denied",
+            GQLRSErrorType::InvalidHeaders(_) => "invalid-headers",
+            GQLRSErrorType::InvalidInput(_) => "validation-failed",
+            GQLRSErrorType::AuthHookError(_) => "auth-hook-error",
+            GQLRSErrorType::PermissionCheckFailed(_) => "permission-error",
+            _ => "unexpected",
+        }
+    }
+
+    /// The error as a GraphQL response, in the shape that the GraphQL spec lays out
+    pub fn to_graphql_errors(&self) -> serde_json::Value {
+        serde_json::json!({
+            "errors": [{
+                "message": self.kind.to_string(),
+                "extensions": { "path": "$", "code": self.code() }
+            }]
+        })
+    }
+}
+
+#[test]
+fn only_header_errors_have_the_invalid_headers_code() {
+    let header_error = GQLRSError::new(GQLRSErrorType::InvalidHeaders(String::from(
+        "Missing `Authorization` header",
+    )));
+    assert_eq!(header_error.code(), "invalid-headers");
+
+    let input_error = GQLRSError::new(GQLRSErrorType::InvalidInput(String::from(
+        "Unknown operator `_matches` on the column `id`",
+    )));
+    assert_eq!(input_error.code(), "validation-failed");
 }
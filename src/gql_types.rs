@@ -2,8 +2,11 @@ use indexmap::IndexMap;
 use serde::Serialize;
 
 use crate::error;
+use crate::metadata::{NamingConvention, TableMetadata};
 use crate::utils;
 
+pub const TYPENAME_FIELD: &str = "__typename";
+
 #[derive(Debug, Serialize, Clone, PartialEq, Eq, std::hash::Hash)]
 pub struct FieldName(
     pub Option<String>,
@@ -17,29 +20,44 @@ impl FieldName {
         FieldName(field_alias, field_name.to_string())
     }
 
-    pub fn to_sql(&self) -> String {
+    /// Selects the column that the field maps to (as per the configuration of its
+    /// table), by the name with which the field appears in the response
+    pub fn to_sql(
+        &self,
+        table: &TableMetadata,
+        naming_convention: NamingConvention,
+    ) -> Result<String, error::GQLRSError> {
         let mut alias = String::from(&self.1);
         if let Some(a) = self.0.clone() {
             alias = a;
         }
 
-        format!(
+        // NOTE: `__typename` is the only meta field that is supported as of now
+        if self.1 == TYPENAME_FIELD {
+            return Ok(format!(
+                "{} AS {}",
+                utils::squote(&table.type_name(naming_convention)),
+                utils::dquote(&alias)
+            ));
+        }
+
+        Ok(format!(
             "{} AS {}",
-            utils::dquote(self.1.as_str()),
+            utils::dquote(&table.column_name(&self.1, naming_convention)?),
             utils::dquote(&alias)
-        )
+        ))
     }
 
     pub fn name(&self) -> String {
         self.1.clone()
     }
 
-    pub fn alias(&self) -> String {
-        if let Some(alias) = self.0.clone() {
-            return utils::dquote(&alias);
+    /// The key with which this field appears in the response
+    pub fn output_name(&self) -> String {
+        match &self.0 {
+            Some(alias) => alias.clone(),
+            None => self.1.clone(),
         }
-
-        utils::dquote(&self.1)
     }
 }
 
@@ -54,11 +72,26 @@ pub fn field_names_to_name_list(nl: &[FieldName]) -> Vec<String> {
     names
 }
 
+#[cfg(test)]
+fn test_table() -> TableMetadata {
+    let mut table = TableMetadata::new(crate::metadata::QualifiedTable::new(
+        "public",
+        "user_profiles",
+    ));
+    table.db_columns = ["users", "usr_nm", "user_name", "address_line1", "a__b"]
+        .iter()
+        .map(|column| String::from(*column))
+        .collect();
+    table
+}
+
 #[test]
 fn field_name_to_sql_with_no_alias() {
     let new_field_name = FieldName::new("users", None);
     assert_eq!(
-        new_field_name.to_sql(),
+        new_field_name
+            .to_sql(&test_table(), NamingConvention::HasuraDefault)
+            .unwrap(),
         "\"users\" AS \"users\"".to_string()
     );
 }
@@ -67,11 +100,76 @@ fn field_name_to_sql_with_no_alias() {
 fn field_name_to_sql_with_alias() {
     let new_field_name = FieldName::new("users", Some("new_users".to_string()));
     assert_eq!(
-        new_field_name.to_sql(),
+        new_field_name
+            .to_sql(&test_table(), NamingConvention::HasuraDefault)
+            .unwrap(),
         "\"users\" AS \"new_users\"".to_string()
     );
 }
 
+#[test]
+fn field_name_to_sql_with_column_custom_name() {
+    let mut table = test_table();
+    table.configuration.column_config.insert(
+        String::from("usr_nm"),
+        crate::metadata::ColumnConfig {
+            custom_name: Some(String::from("user_name")),
+        },
+    );
+
+    let new_field_name = FieldName::new("user_name", None);
+    assert_eq!(
+        new_field_name
+            .to_sql(&table, NamingConvention::GraphqlDefault)
+            .unwrap(),
+        "\"usr_nm\" AS \"user_name\"".to_string()
+    );
+
+    // the column can't be referred to by its name in the database anymore
+    let raw_field_name = FieldName::new("usr_nm", None);
+    assert!(raw_field_name
+        .to_sql(&table, NamingConvention::HasuraDefault)
+        .is_err());
+}
+
+#[test]
+fn field_name_to_sql_with_graphql_default_naming_convention() {
+    let new_field_name = FieldName::new("userName", None);
+    assert_eq!(
+        new_field_name
+            .to_sql(&test_table(), NamingConvention::GraphqlDefault)
+            .unwrap(),
+        "\"user_name\" AS \"userName\"".to_string()
+    );
+
+    // the field names are matched with the columns by converting the column names
+    for (field_name, column_name) in [("addressLine1", "address_line1"), ("aB", "a__b")] {
+        assert_eq!(
+            FieldName::new(field_name, None)
+                .to_sql(&test_table(), NamingConvention::GraphqlDefault)
+                .unwrap(),
+            format!("\"{}\" AS \"{}\"", column_name, field_name)
+        );
+    }
+    assert!(FieldName::new("addressLine_1", None)
+        .to_sql(&test_table(), NamingConvention::GraphqlDefault)
+        .is_err());
+
+    let typename_field = FieldName::new(TYPENAME_FIELD, None);
+    assert_eq!(
+        typename_field
+            .to_sql(&test_table(), NamingConvention::GraphqlDefault)
+            .unwrap(),
+        "'UserProfiles' AS \"__typename\"".to_string()
+    );
+    assert_eq!(
+        typename_field
+            .to_sql(&test_table(), NamingConvention::HasuraDefault)
+            .unwrap(),
+        "'user_profiles' AS \"__typename\"".to_string()
+    );
+}
+
 /* NOTE: GQLArgs is a simplified version of the
     AST's representation of arguments. Using
     such a structure only because it's easier
@@ -90,6 +188,8 @@ pub enum GQLArgType<T> {
     String(String),
     // NOTE: supported for [limit, offset]
     Int(i64),
+    // NOTE: supported for [limit, offset], this holds the name of the variable
+    Variable(String),
     // NOTE: supported for [order_by]
     Object(IndexMap<String, T>),
 }
@@ -138,10 +238,53 @@ impl<T> GQLArgType<T> {
     }
 }
 
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub enum CursorOrdering {
+    Asc,
+    Desc,
+}
+
+impl CursorOrdering {
+    pub fn to_sql(&self) -> &str {
+        match self {
+            CursorOrdering::Asc => "ASC",
+            CursorOrdering::Desc => "DESC",
+        }
+    }
+
+    /// The comparison operator that picks the rows that come after the cursor
+    pub fn comparison_sql(&self) -> &str {
+        match self {
+            CursorOrdering::Asc => ">",
+            CursorOrdering::Desc => "<",
+        }
+    }
+}
+
+/// The cursor of a streaming root field, i.e. `<table>_stream`. The value of the
+/// cursor is kept with the variables under `STREAM_CURSOR_VARIABLE`, so that it
+/// can be different for every subscriber of a multiplexed streaming subscription
+#[derive(Serialize, Clone, Debug)]
+pub struct StreamCursor {
+    pub column: String,
+    pub ordering: CursorOrdering,
+}
+
+pub const STREAM_ROOT_FIELD_SUFFIX: &str = "_stream";
+
+// NOTE: GraphQL reserves names starting with `__`, so this can't clash with any
+// of the variables supplied with a request
+pub const STREAM_CURSOR_VARIABLE: &str = "__cursor";
+
+// NOTE: the session variables of a request are kept with its variables too, so that
+// the subscribers of a multiplexed subscription can have sessions of their own
+pub const SESSION_VARIABLES_VARIABLE: &str = "__session";
+
 #[derive(Serialize, Clone, Debug)]
 pub struct FieldInfo {
     fields: Vec<FieldName>,
     root_field_arguments: indexmap::IndexMap<String, GQLArgTypeWithOrderBy>,
+    stream_cursor: Option<StreamCursor>,
 }
 
 impl FieldInfo {
@@ -149,9 +292,19 @@ impl FieldInfo {
         FieldInfo {
             fields,
             root_field_arguments: args,
+            stream_cursor: None,
         }
     }
 
+    pub fn with_stream_cursor(mut self, stream_cursor: StreamCursor) -> FieldInfo {
+        self.stream_cursor = Some(stream_cursor);
+        self
+    }
+
+    pub fn stream_cursor(&self) -> Option<&StreamCursor> {
+        self.stream_cursor.as_ref()
+    }
+
     pub fn args(&self) -> &indexmap::IndexMap<String, GQLArgTypeWithOrderBy> {
         &self.root_field_arguments
     }
@@ -159,8 +312,142 @@ impl FieldInfo {
     pub fn fields(&self) -> &[FieldName] {
         &self.fields
     }
+
+    /// The names of the variables that are used in the arguments
+    pub fn variables(&self) -> Vec<String> {
+        self.root_field_arguments
+            .values()
+            .filter_map(|arg| match arg {
+                GQLArgType::Variable(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A field of the response of a mutation root field
+#[derive(Serialize, Clone, Debug)]
+pub enum MutationOutputField {
+    AffectedRows(FieldName),
+    // NOTE: these are the fields of the rows that the mutation returns
+    Returning(FieldName, Vec<FieldName>),
+    Typename(FieldName),
+}
+
+/// A mutation root field, whose arguments (`objects`, `where` & `_set`) are kept as JSON,
+/// with the values of the variables that they use filled in
+#[derive(Serialize, Clone, Debug)]
+pub struct MutationFieldInfo {
+    arguments: IndexMap<String, serde_json::Value>,
+    output_fields: Vec<MutationOutputField>,
+}
+
+impl MutationFieldInfo {
+    pub fn new(
+        arguments: IndexMap<String, serde_json::Value>,
+        output_fields: Vec<MutationOutputField>,
+    ) -> MutationFieldInfo {
+        MutationFieldInfo {
+            arguments,
+            output_fields,
+        }
+    }
+
+    pub fn argument(&self, name: &str) -> Option<&serde_json::Value> {
+        self.arguments.get(name)
+    }
+
+    pub fn output_fields(&self) -> &[MutationOutputField] {
+        &self.output_fields
+    }
+}
+
+/// The root fields of a mutation, which are run in the order in which they appear
+pub type MutationRootFields = IndexMap<FieldName, MutationFieldInfo>;
+
+/// The values of the variables supplied with a GraphQL request
+pub type QueryVariableValues = IndexMap<String, serde_json::Value>;
+
+/// This decides how the variables used in a query make it into its SQL
+pub enum QueryVariables<'a> {
+    // NOTE: the values of the variables are inlined into the query
+    Values(&'a QueryVariableValues),
+    // NOTE: the values are read off of a column that holds a JSON object of the
+    // variables, which is what lets us run a query for many sets of variables at once
+    Column(&'a str),
+}
+
+impl<'a> QueryVariables<'a> {
+    pub fn int_to_sql(&self, variable_name: &str) -> Result<String, error::GQLRSError> {
+        match self {
+            QueryVariables::Values(values) => values
+                .get(variable_name)
+                .and_then(|value| value.as_i64())
+                .map(|num| num.to_string())
+                .ok_or_else(|| {
+                    error::GQLRSError::new(error::GQLRSErrorType::InvalidInput(format!(
+                        "Variable `${}` must be an Int",
+                        variable_name
+                    )))
+                }),
+            QueryVariables::Column(column) => Ok(format!(
+                "(({}->>{})::bigint)",
+                column,
+                utils::squote(variable_name)
+            )),
+        }
+    }
+
+    /// The value of a session variable (like `X-Hasura-User-Id`), as an untyped literal
+    pub fn session_variable_to_sql(
+        &self,
+        session_variable: &str,
+    ) -> Result<String, error::GQLRSError> {
+        let session_variable = session_variable.to_lowercase();
+        match self {
+            QueryVariables::Values(values) => values
+                .get(SESSION_VARIABLES_VARIABLE)
+                .and_then(|session| session.get(&session_variable))
+                .and_then(|value| value.as_str())
+                .map(utils::squote)
+                .ok_or_else(|| {
+                    error::GQLRSError::new(error::GQLRSErrorType::InvalidInput(format!(
+                        "Missing session variable `{}`",
+                        session_variable
+                    )))
+                }),
+            // NOTE: the query fails to find any rows, rather than failing
+            // outright, for the subscribers that don't have the session variable
+            QueryVariables::Column(column) => Ok(format!(
+                "({}->{}->>{})",
+                column,
+                utils::squote(SESSION_VARIABLES_VARIABLE),
+                utils::squote(&session_variable)
+            )),
+        }
+    }
+
+    pub fn json_to_sql(&self, variable_name: &str) -> Result<String, error::GQLRSError> {
+        match self {
+            QueryVariables::Values(values) => values
+                .get(variable_name)
+                .map(|value| format!("{}::json", utils::squote(&value.to_string())))
+                .ok_or_else(|| {
+                    error::GQLRSError::new(error::GQLRSErrorType::InvalidInput(format!(
+                        "Variable `${}` was not supplied",
+                        variable_name
+                    )))
+                }),
+            QueryVariables::Column(column) => {
+                Ok(format!("({}->{})", column, utils::squote(variable_name)))
+            }
+        }
+    }
 }
 
+/// The root fields of an operation, in the order in which they were queried
+pub type RootFields = IndexMap<FieldName, FieldInfo>;
+
 pub fn to_string_arg<'a>(
     arg_name: String,
     arg_val: &graphql_parser::query::Value<'a, &'a str>,
@@ -189,6 +476,10 @@ pub fn to_int_arg<'a>(
         }
     }
 
+    if let graphql_parser::query::Value::Variable(name) = arg_val {
+        return Ok((arg_name, GQLArgType::Variable(name.to_string())));
+    }
+
     Err(error::GQLRSError::new(error::GQLRSErrorType::GenericError(
         format!("failed to parse argument {}", arg_name),
     )))
@@ -223,11 +514,14 @@ pub fn is_order_by_keys_valid<'a>(
     false
 }
 
-pub fn to_object_arg<'a, T>(
+pub fn to_object_arg<'a, T, F>(
     arg_name: String,
     arg_val: &graphql_parser::query::Value<'a, &'a str>,
-    make_value_fn: fn(v: graphql_parser::query::Value<'a, &'a str>) -> Option<T>,
-) -> Result<(String, GQLArgType<T>), error::GQLRSError> {
+    make_value_fn: F,
+) -> Result<(String, GQLArgType<T>), error::GQLRSError>
+where
+    F: Fn(graphql_parser::query::Value<'a, &'a str>) -> Option<T>,
+{
     if let graphql_parser::query::Value::Object(arg_bmap) = arg_val {
         let mut arg_map: IndexMap<String, T> = IndexMap::new();
 
@@ -282,25 +576,33 @@ impl OrderByOptions {
 
 pub fn from_parser_value_to_order_by_option<'a>(
     val: graphql_parser::query::Value<'a, &'a str>,
+    naming_convention: NamingConvention,
 ) -> Option<OrderByOptions> {
     if let graphql_parser::query::Value::Enum(str_val) = val {
-        return to_order_by_option_value(str_val);
+        return to_order_by_option_value(str_val, naming_convention);
     }
 
     None
 }
 
 // TODO: use `serde` for this purpose instead
-fn to_order_by_option_value(v: &str) -> Option<OrderByOptions> {
-    match v {
-        "asc" => Some(OrderByOptions::Asc),
-        "asc_nulls_first" => Some(OrderByOptions::AscNullsFirst),
-        "asc_nulls_last" => Some(OrderByOptions::AscNullsLast),
-        "desc" => Some(OrderByOptions::Desc),
-        "desc_nulls_first" => Some(OrderByOptions::DescNullsFirst),
-        "desc_nulls_last" => Some(OrderByOptions::DescNullsLast),
-        _ => None,
-    }
+fn to_order_by_option_value(
+    v: &str,
+    naming_convention: NamingConvention,
+) -> Option<OrderByOptions> {
+    let order_by_options = [
+        ("asc", OrderByOptions::Asc),
+        ("asc_nulls_first", OrderByOptions::AscNullsFirst),
+        ("asc_nulls_last", OrderByOptions::AscNullsLast),
+        ("desc", OrderByOptions::Desc),
+        ("desc_nulls_first", OrderByOptions::DescNullsFirst),
+        ("desc_nulls_last", OrderByOptions::DescNullsLast),
+    ];
+
+    order_by_options
+        .iter()
+        .find(|(option_name, _)| naming_convention.enum_value(option_name) == v)
+        .map(|(_, option)| option.clone())
 }
 
 // NOTE: these argument names are case sensitive, in case they're
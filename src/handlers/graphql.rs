@@ -1,17 +1,26 @@
+use actix_web::error::BlockingError;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use indexmap::IndexMap;
 use log::warn;
 use postgres::types::Json;
-use postgres::{Client, Row};
+use postgres::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use crate::gql_types::{
     field_names_to_name_list, from_parser_value_to_order_by_option, is_order_by_keys_valid,
-    to_int_arg, to_object_arg, to_string_arg, FieldInfo, FieldName, GQLArgType, OrderByOptions,
+    to_int_arg, to_object_arg, to_string_arg, CursorOrdering, FieldInfo, FieldName, GQLArgType,
+    MutationFieldInfo, MutationOutputField, MutationRootFields, OrderByOptions,
+    QueryVariableValues, RootFields, StreamCursor, STREAM_CURSOR_VARIABLE,
+    STREAM_ROOT_FIELD_SUFFIX, TYPENAME_FIELD,
+};
+use crate::metadata::{Metadata, NamingConvention};
+use crate::session::SessionVariables;
+use crate::{
+    context::{pooled_client, AppState, PGPool, PermissionsMode},
+    db,
+    utils::map_either,
 };
-use crate::metadata::Metadata;
-use crate::{context::AppState, db, utils::map_either};
 
 fn get_data_json<T>(data_arg: T) -> serde_json::Value
 where
@@ -63,7 +72,7 @@ impl actix_web::Responder for GraphQLResponse {
 }
 
 #[inline(always)]
-pub fn empty_query_variables() -> IndexMap<String, String> {
+pub fn empty_query_variables() -> QueryVariableValues {
     IndexMap::new()
 }
 
@@ -71,20 +80,305 @@ pub fn empty_query_variables() -> IndexMap<String, String> {
 pub struct GraphQLRequest {
     pub query: String,
     #[serde(default = "empty_query_variables")]
-    pub variables: IndexMap<String, String>,
+    pub variables: QueryVariableValues,
+}
+
+pub type GQLResult = IndexMap<String, serde_json::Value>;
+
+/// The root fields of an operation along with the values of the variables that it uses
+#[derive(Debug, Clone)]
+pub struct OperationFields {
+    pub root_fields: RootFields,
+    pub variables: QueryVariableValues,
+}
+
+/// The root fields of a mutation, whose arguments have the values of the variables
+/// filled in already
+#[derive(Debug, Clone)]
+pub struct MutationFields {
+    pub root_fields: MutationRootFields,
+}
+
+/// The (first) operation of a GraphQL request
+pub enum GraphQLOperation {
+    Query(OperationFields),
+    Mutation(MutationFields),
+    Subscription(OperationFields),
+}
+
+/// Parses the (first) operation of a request, with the names of the arguments (and
+/// their enum values) as per the naming convention of the source
+pub fn parse_graphql_operation(
+    request: &GraphQLRequest,
+    naming_convention: NamingConvention,
+) -> Result<GraphQLOperation, String> {
+    match graphql_parser::parse_query::<&str>(&request.query) {
+        // NOTE: We only execute the first query/mutation/subscription that
+        // gets matched/parsed. Similar to what Hasura does
+        Ok(q) => match &q.definitions[0] {
+            graphql_parser::query::Definition::Fragment(_) => {
+                Err(String::from("Fragments are not supported"))
+            }
+            graphql_parser::query::Definition::Operation(op) => match op {
+                graphql_parser::query::OperationDefinition::Mutation(mutation) => {
+                    get_mutation_fields(
+                        &mutation.selection_set,
+                        &mutation.variable_definitions,
+                        &request.variables,
+                        naming_convention,
+                    )
+                    .map(GraphQLOperation::Mutation)
+                }
+                graphql_parser::query::OperationDefinition::Subscription(sub) => {
+                    get_operation_fields(
+                        &sub.selection_set,
+                        &sub.variable_definitions,
+                        &request.variables,
+                        naming_convention,
+                    )
+                    .and_then(check_stream_subscription)
+                    .map(GraphQLOperation::Subscription)
+                }
+                graphql_parser::query::OperationDefinition::Query(qry) => get_operation_fields(
+                    &qry.selection_set,
+                    &qry.variable_definitions,
+                    &request.variables,
+                    naming_convention,
+                )
+                .and_then(check_no_stream_fields)
+                .map(GraphQLOperation::Query),
+                graphql_parser::query::OperationDefinition::SelectionSet(sel_set) => {
+                    get_operation_fields(sel_set, &[], &request.variables, naming_convention)
+                        .and_then(check_no_stream_fields)
+                        .map(GraphQLOperation::Query)
+                }
+            },
+        },
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn has_stream_fields(operation_fields: &OperationFields) -> bool {
+    operation_fields
+        .root_fields
+        .values()
+        .any(|field_info| field_info.stream_cursor().is_some())
 }
 
-type GQLResult = IndexMap<String, serde_json::Value>;
+fn check_no_stream_fields(operation_fields: OperationFields) -> Result<OperationFields, String> {
+    if has_stream_fields(&operation_fields) {
+        return Err(String::from(
+            "Streaming root fields can only be used in subscriptions",
+        ));
+    }
+    Ok(operation_fields)
+}
 
-fn fetch_result_from_query_fields<'a>(
+// NOTE: the cursor of a streaming subscription is kept with the variables of
+// the subscriber, so there can only be one streaming root field per subscription
+fn check_stream_subscription(operation_fields: OperationFields) -> Result<OperationFields, String> {
+    if has_stream_fields(&operation_fields) && operation_fields.root_fields.len() > 1 {
+        return Err(String::from(
+            "A streaming subscription can only have a single root field",
+        ));
+    }
+    Ok(operation_fields)
+}
+
+fn get_operation_fields<'a>(
+    qry_sel_set: &graphql_parser::query::SelectionSet<'a, &'a str>,
+    variable_definitions: &[graphql_parser::query::VariableDefinition<'a, &'a str>],
+    supplied_variables: &QueryVariableValues,
+    naming_convention: NamingConvention,
+) -> Result<OperationFields, String> {
+    let mut variables = operation_variables(variable_definitions, supplied_variables);
+
+    let root_fields = get_root_fields(qry_sel_set, &mut variables, naming_convention)?;
+
+    // NOTE: variables are only supported for the `limit` & `offset` arguments as of now
+    for field_info in root_fields.values() {
+        for variable_name in field_info.variables() {
+            if !variables
+                .get(&variable_name)
+                .is_some_and(|value| value.is_i64())
+            {
+                return Err(format!("Variable `${}` must be an Int", variable_name));
+            }
+        }
+    }
+
+    Ok(OperationFields {
+        root_fields,
+        variables,
+    })
+}
+
+/// The values of the variables of an operation, where the default values of the
+/// variables are overridden by the supplied ones
+fn operation_variables<'a>(
+    variable_definitions: &[graphql_parser::query::VariableDefinition<'a, &'a str>],
+    supplied_variables: &QueryVariableValues,
+) -> QueryVariableValues {
+    let mut variables: QueryVariableValues = IndexMap::new();
+    for variable_definition in variable_definitions.iter() {
+        if let Some(default_value) = &variable_definition.default_value {
+            variables.insert(
+                variable_definition.name.to_string(),
+                parser_value_to_json(default_value),
+            );
+        }
+    }
+    for (variable_name, variable_value) in supplied_variables.iter() {
+        variables.insert(variable_name.clone(), variable_value.clone());
+    }
+
+    variables
+}
+
+// NOTE: the names of the arguments of the mutation root fields are the same with
+// either of the naming conventions
+const MUTATION_ARGUMENT_NAMES: [&str; 3] = ["objects", "where", "_set"];
+
+fn get_mutation_fields<'a>(
+    mutation_sel_set: &graphql_parser::query::SelectionSet<'a, &'a str>,
+    variable_definitions: &[graphql_parser::query::VariableDefinition<'a, &'a str>],
+    supplied_variables: &QueryVariableValues,
+    naming_convention: NamingConvention,
+) -> Result<MutationFields, String> {
+    let variables = operation_variables(variable_definitions, supplied_variables);
+    let mut root_fields: MutationRootFields = IndexMap::new();
+
+    for set in mutation_sel_set.items.iter() {
+        if let graphql_parser::query::Selection::Field(field) = set {
+            let root_field_name = FieldName::new(field.name, field.alias.map(String::from));
+
+            let mut arguments: IndexMap<String, serde_json::Value> = IndexMap::new();
+            for (arg_name, arg_value) in field.arguments.iter() {
+                if MUTATION_ARGUMENT_NAMES.contains(arg_name) {
+                    arguments.insert(
+                        arg_name.to_string(),
+                        argument_value_to_json(arg_value, &variables)?,
+                    );
+                } else {
+                    warn!(
+                        "Arguement `{}` isn't supported for mutations and hence being ignored",
+                        arg_name
+                    );
+                }
+            }
+
+            let output_fields =
+                mutation_output_fields(field.name, &field.selection_set, naming_convention)?;
+            root_fields.insert(
+                root_field_name,
+                MutationFieldInfo::new(arguments, output_fields),
+            );
+        }
+    }
+
+    Ok(MutationFields { root_fields })
+}
+
+/// The fields of the response of a mutation root field, i.e. `affected_rows`, `returning`
+/// (with the fields of the rows) and `__typename`
+fn mutation_output_fields<'a>(
+    root_field_name: &str,
+    sel_set: &graphql_parser::query::SelectionSet<'a, &'a str>,
+    naming_convention: NamingConvention,
+) -> Result<Vec<MutationOutputField>, String> {
+    let mut output_fields: Vec<MutationOutputField> = Vec::new();
+
+    for set_item in sel_set.items.iter() {
+        if let graphql_parser::query::Selection::Field(fld) = set_item {
+            let field_name = FieldName::new(fld.name, fld.alias.map(String::from));
+            let output_field = if fld.name == TYPENAME_FIELD {
+                MutationOutputField::Typename(field_name)
+            } else if fld.name == naming_convention.field_name("affected_rows") {
+                MutationOutputField::AffectedRows(field_name)
+            } else if fld.name == "returning" {
+                MutationOutputField::Returning(
+                    field_name,
+                    selection_set_fields_parser(&fld.selection_set),
+                )
+            } else {
+                return Err(format!(
+                    "Field `{}` not found in the response of `{}`",
+                    fld.name, root_field_name
+                ));
+            };
+            output_fields.push(output_field);
+        }
+    }
+
+    if output_fields.is_empty() {
+        return Err(format!(
+            "The mutation root field `{}` needs a selection set",
+            root_field_name
+        ));
+    }
+
+    Ok(output_fields)
+}
+
+/// The value of an argument as JSON, with the values of the variables that it uses
+fn argument_value_to_json<'a>(
+    value: &graphql_parser::query::Value<'a, &'a str>,
+    variables: &QueryVariableValues,
+) -> Result<serde_json::Value, String> {
+    match value {
+        graphql_parser::query::Value::Variable(variable_name) => variables
+            .get(*variable_name)
+            .cloned()
+            .ok_or_else(|| format!("Variable `${}` was not supplied", variable_name)),
+        graphql_parser::query::Value::List(values) => values
+            .iter()
+            .map(|value| argument_value_to_json(value, variables))
+            .collect::<Result<Vec<serde_json::Value>, String>>()
+            .map(serde_json::Value::Array),
+        graphql_parser::query::Value::Object(obj) => obj
+            .iter()
+            .map(|(key, value)| {
+                argument_value_to_json(value, variables).map(|value| (key.to_string(), value))
+            })
+            .collect::<Result<serde_json::Map<String, serde_json::Value>, String>>()
+            .map(serde_json::Value::Object),
+        _ => Ok(parser_value_to_json(value)),
+    }
+}
+
+fn parser_value_to_json<'a>(
+    value: &graphql_parser::query::Value<'a, &'a str>,
+) -> serde_json::Value {
+    match value {
+        graphql_parser::query::Value::Int(num) => json!(num.as_i64()),
+        graphql_parser::query::Value::Float(num) => json!(num),
+        graphql_parser::query::Value::String(txt) => json!(txt),
+        graphql_parser::query::Value::Boolean(flag) => json!(flag),
+        graphql_parser::query::Value::Enum(txt) => json!(txt),
+        graphql_parser::query::Value::List(values) => {
+            serde_json::Value::Array(values.iter().map(parser_value_to_json).collect())
+        }
+        graphql_parser::query::Value::Object(obj) => serde_json::Value::Object(
+            obj.iter()
+                .map(|(key, value)| (key.to_string(), parser_value_to_json(value)))
+                .collect(),
+        ),
+        // NOTE: variables can't be used within the default values of variables
+        graphql_parser::query::Value::Variable(_) | graphql_parser::query::Value::Null => {
+            serde_json::Value::Null
+        }
+    }
+}
+
+fn get_root_fields<'a>(
     qry_sel_set: &graphql_parser::query::SelectionSet<'a, &'a str>,
     // NOTE: since we're not using any specific information from the query we could
     // move to using the selection set without having to duplicating code for
-    // many of the patterns, like Query, Selection Set and eventually Subscriptions!
-    pg_client: &mut Client,
-    metadata: &Metadata,
-) -> Result<GQLResult, String> {
-    let mut fields_map: IndexMap<FieldName, FieldInfo> = IndexMap::new();
+    // many of the patterns, like Query, Selection Set and Subscriptions!
+    variables: &mut QueryVariableValues,
+    naming_convention: NamingConvention,
+) -> Result<RootFields, String> {
+    let mut fields_map: RootFields = IndexMap::new();
 
     for set in qry_sel_set.items.iter() {
         // NOTE: Nothing's being done for the other variants of the Selection enum
@@ -96,39 +390,46 @@ fn fetch_result_from_query_fields<'a>(
             let mut field_args: IndexMap<String, GQLArgType<OrderByOptions>> = IndexMap::new();
             let sub_fields = selection_set_fields_parser(&field.selection_set);
 
+            if is_stream_field(field, naming_convention) {
+                let field_info =
+                    get_stream_field_info(field, sub_fields, variables, naming_convention)?;
+                fields_map.insert(root_field_name, field_info);
+                continue;
+            }
+
             if !field.arguments.is_empty() {
                 for root_field_arg in field.arguments.iter() {
                     let arg_name = root_field_arg.0.to_string();
                     let arg_value = &root_field_arg.1;
-                    match arg_name.as_str() {
-                        "order_by" => {
+                    match argument_name(&arg_name, naming_convention) {
+                        Some("order_by") => {
                             let str_field_names = field_names_to_name_list(&sub_fields);
                             if !is_order_by_keys_valid(&str_field_names, arg_value) {
                                 let err_msg = format!("Invalid argument values supplied to `order_by`: {}. The keys must be one off {:?} and should be used at most once", arg_value, str_field_names);
                                 return Err(err_msg);
                             }
-                            let convert_to_object_arg = to_object_arg(
-                                arg_name.to_string(),
-                                arg_value,
-                                from_parser_value_to_order_by_option,
-                            );
+                            let convert_to_object_arg =
+                                to_object_arg(String::from("order_by"), arg_value, |value| {
+                                    from_parser_value_to_order_by_option(value, naming_convention)
+                                });
                             if let Ok(fa) = convert_to_object_arg {
                                 field_args.insert(fa.0, fa.1);
                             } else if let Err(e) = convert_to_object_arg {
                                 return Err(e.to_string());
                             }
                         }
-                        "limit" | "offset" => {
-                            let convert_to_int_arg = to_int_arg(arg_name.to_string(), arg_value);
+                        Some(int_arg_name @ ("limit" | "offset")) => {
+                            let convert_to_int_arg =
+                                to_int_arg(int_arg_name.to_string(), arg_value);
                             if let Ok(fa) = convert_to_int_arg {
                                 field_args.insert(fa.0, fa.1);
                             } else if let Err(e) = convert_to_int_arg {
                                 return Err(e.to_string());
                             }
                         }
-                        "distinct_on" => {
+                        Some("distinct_on") => {
                             let convert_to_string_arg =
-                                to_string_arg(arg_name.to_string(), arg_value);
+                                to_string_arg(String::from("distinct_on"), arg_value);
                             match convert_to_string_arg {
                                 Ok(fa) => {
                                     let str_fields = field_names_to_name_list(&sub_fields);
@@ -161,50 +462,162 @@ fn fetch_result_from_query_fields<'a>(
         }
     }
 
-    let mut result_rows: Vec<Row> = Vec::new();
-    for field_info in fields_map.iter() {
-        let root_field_name = field_info.0;
-        let fields_info_struct = field_info.1;
-
-        let query_res = db::get_rows_gql_query(
-            pg_client,
-            root_field_name,
-            fields_info_struct,
-            metadata.to_owned(),
-        );
-        match query_res {
-            Ok(db_res) => {
-                result_rows.push(db_res);
-            }
-            // NOTE: this error is encounted when the query fails at the DB
-            Err(db_err) => {
-                return Err(db_err.to_string());
+    Ok(fields_map)
+}
+
+pub fn fetch_result_from_operation_fields(
+    pg_client: &mut Client,
+    operation_fields: &OperationFields,
+    metadata: &Metadata,
+    session_variables: &SessionVariables,
+    permissions_mode: PermissionsMode,
+) -> Result<GQLResult, String> {
+    let result_row = db::get_rows_gql_query(
+        pg_client,
+        &operation_fields.root_fields,
+        metadata,
+        &operation_fields.variables,
+        session_variables,
+        permissions_mode,
+    )
+    // NOTE: this error is encounted when the query fails at the DB
+    .map_err(|db_err| db_err.to_string())?;
+
+    let query_result: Result<Json<GQLResult>, postgres::Error> = result_row.try_get("data");
+
+    query_result
+        .map(|result| result.0)
+        .map_err(|err| err.to_string())
+}
+
+// NOTE: the names of the arguments (and of the fields of their input objects) as per the
+// `hasura-default` naming convention, which is what the arguments are matched by
+const ARGUMENT_NAMES: [&str; 8] = [
+    "order_by",
+    "limit",
+    "offset",
+    "distinct_on",
+    "batch_size",
+    "cursor",
+    "initial_value",
+    "ordering",
+];
+
+/// Maps the name of an argument back to its `hasura-default` name, so that e.g. `orderBy`
+/// is treated as `order_by` with `graphql-default` (while `order_by` isn't known at all)
+fn argument_name(arg_name: &str, naming_convention: NamingConvention) -> Option<&'static str> {
+    ARGUMENT_NAMES
+        .iter()
+        .find(|name| naming_convention.field_name(name) == arg_name)
+        .copied()
+}
+
+/// A `<table>_stream` root field with a `cursor` argument is a streaming root field
+fn is_stream_field<'a>(
+    field: &graphql_parser::query::Field<'a, &'a str>,
+    naming_convention: NamingConvention,
+) -> bool {
+    field
+        .name
+        .ends_with(&naming_convention.root_field_suffix(STREAM_ROOT_FIELD_SUFFIX))
+        && field
+            .arguments
+            .iter()
+            .any(|(arg_name, _)| argument_name(arg_name, naming_convention) == Some("cursor"))
+}
+
+// NOTE: the arguments of a streaming root field look like:
+// `batch_size: Int!, cursor: { initial_value: { <column>: <value> }, ordering: ASC | DESC }`
+fn get_stream_field_info<'a>(
+    field: &graphql_parser::query::Field<'a, &'a str>,
+    sub_fields: Vec<FieldName>,
+    variables: &mut QueryVariableValues,
+    naming_convention: NamingConvention,
+) -> Result<FieldInfo, String> {
+    let mut field_args: IndexMap<String, GQLArgType<OrderByOptions>> = IndexMap::new();
+    let mut stream_cursor: Option<StreamCursor> = None;
+
+    for (arg_name, arg_value) in field.arguments.iter() {
+        match argument_name(arg_name, naming_convention) {
+            // NOTE: the size of a batch is just the `limit` of the query
+            Some("batch_size") => {
+                let batch_size =
+                    to_int_arg(String::from("limit"), arg_value).map_err(|err| err.to_string())?;
+                field_args.insert(batch_size.0, batch_size.1);
             }
-        }
-    }
+            Some("cursor") => {
+                let cursor_fields = match arg_value {
+                    graphql_parser::query::Value::Object(cursor_fields) => cursor_fields,
+                    _ => return Err(String::from("The value for `cursor` should be an object")),
+                };
+
+                let ordering = match cursor_fields
+                    .get(naming_convention.field_name("ordering").as_str())
+                {
+                    None | Some(graphql_parser::query::Value::Enum("ASC")) => CursorOrdering::Asc,
+                    Some(graphql_parser::query::Value::Enum("DESC")) => CursorOrdering::Desc,
+                    Some(other) => {
+                        return Err(format!(
+                            "The value for `ordering` should be one of ASC or DESC, found {}",
+                            other
+                        ))
+                    }
+                };
 
-    let mut final_res: GQLResult = IndexMap::new();
+                let initial_value = match cursor_fields
+                    .get(naming_convention.field_name("initial_value").as_str())
+                {
+                    Some(graphql_parser::query::Value::Object(initial_value))
+                        if initial_value.len() == 1 =>
+                    {
+                        initial_value
+                    }
+                    _ => {
+                        return Err(String::from(
+                            "The `initial_value` of a `cursor` should be an object with exactly one column",
+                        ))
+                    }
+                };
+
+                for (column, value) in initial_value.iter() {
+                    let value = match value {
+                        graphql_parser::query::Value::Variable(variable_name) => {
+                            variables.get(*variable_name).cloned().ok_or_else(|| {
+                                format!("Variable `${}` was not supplied", variable_name)
+                            })?
+                        }
+                        _ => parser_value_to_json(value),
+                    };
 
-    for res_row in result_rows.iter() {
-        // FIXME: can be a potential point of failure
-        let root_field_name = res_row.columns()[0].name();
-        let query_result: Result<Json<serde_json::Value>, postgres::Error> =
-            res_row.try_get(root_field_name);
-        match query_result {
-            Ok(result) => {
-                final_res.insert(root_field_name.to_string(), result.0);
+                    variables.insert(
+                        String::from(STREAM_CURSOR_VARIABLE),
+                        json!({ column.to_string(): value }),
+                    );
+                    stream_cursor = Some(StreamCursor {
+                        column: column.to_string(),
+                        ordering: ordering.clone(),
+                    });
+                }
             }
-            // NOTE: this error is reported when we encounter no rows or nulls
-            Err(_err) => {
-                final_res.insert(
-                    root_field_name.to_string(),
-                    serde_json::json!({ root_field_name.to_string(): serde_json::Value::Null }),
+            _ => {
+                warn!(
+                    "Arguement `{}` isn't supported for streaming root fields and hence being ignored",
+                    arg_name
                 );
             }
         }
     }
 
-    Ok(final_res)
+    if !field_args.contains_key("limit") {
+        return Err(format!("`batch_size` is required for `{}`", field.name));
+    }
+
+    match stream_cursor {
+        Some(stream_cursor) => {
+            Ok(FieldInfo::new(sub_fields, field_args).with_stream_cursor(stream_cursor))
+        }
+        None => Err(format!("`cursor` is required for `{}`", field.name)),
+    }
 }
 
 fn selection_set_fields_parser<'a>(
@@ -222,53 +635,128 @@ fn selection_set_fields_parser<'a>(
     fields
 }
 
-// NOTE: Only GraphQL Queries and Selection Sets are supported.
-//       Mutations, Subscriptions will be supported eventually.
+pub fn run_mutation_fields(
+    pg_client: &mut Client,
+    mutation_fields: &MutationFields,
+    metadata: &Metadata,
+    session_variables: &SessionVariables,
+    permissions_mode: PermissionsMode,
+) -> Result<GQLResult, String> {
+    db::run_gql_mutation(
+        pg_client,
+        &mutation_fields.root_fields,
+        metadata,
+        session_variables,
+        permissions_mode,
+    )
+    .map_err(|err| err.to_string())
+}
+
+/// The connection pool, metadata and permissions mode that an operation runs with. They're
+/// copied out of the server context, so that it isn't locked while the operation runs
+/// against the database
+fn operation_context(app_state: &AppState) -> (PGPool, Metadata, PermissionsMode) {
+    let server_ctx = app_state.0.lock().unwrap();
+    (
+        server_ctx.get_connection_pool().clone(),
+        server_ctx.get_metadata().clone(),
+        server_ctx.get_permissions_mode(),
+    )
+}
+
+/// Runs an operation (that blocks on the database) on the thread pool for blocking calls,
+/// so that it doesn't hold up the thread (and the actors on it) that it was started from
+pub async fn run_blocking<F>(operation: F) -> Result<GQLResult, String>
+where
+    F: FnOnce() -> Result<GQLResult, String> + Send + 'static,
+{
+    web::block(operation).await.map_err(|err| match err {
+        BlockingError::Error(err) => err,
+        BlockingError::Canceled => String::from("The operation was canceled"),
+    })
+}
+
+/// Runs a mutation with a connection from the pool of the server, like `run_query_operation`
+pub fn run_mutation_operation(
+    app_state: &AppState,
+    mutation_fields: &MutationFields,
+    session_variables: &SessionVariables,
+) -> Result<GQLResult, String> {
+    let (pg_pool, metadata, permissions_mode) = operation_context(app_state);
+    let mut pg_client = pooled_client(&pg_pool).map_err(|err| err.to_string())?;
+    run_mutation_fields(
+        &mut pg_client,
+        mutation_fields,
+        &metadata,
+        session_variables,
+        permissions_mode,
+    )
+}
+
+// NOTE: Only GraphQL Queries, Mutations and Selection Sets are supported over HTTP.
+//       Subscriptions are served over WebSockets by the `graphql_ws` handler.
+/// Runs a query with a connection from the pool of the server
+pub fn run_query_operation(
+    app_state: &AppState,
+    operation_fields: &OperationFields,
+    session_variables: &SessionVariables,
+) -> Result<GQLResult, String> {
+    let (pg_pool, metadata, permissions_mode) = operation_context(app_state);
+    let mut pg_client = pooled_client(&pg_pool).map_err(|err| err.to_string())?;
+    fetch_result_from_operation_fields(
+        &mut pg_client,
+        operation_fields,
+        &metadata,
+        session_variables,
+        permissions_mode,
+    )
+}
+
 pub async fn graphql_handler(
+    req: HttpRequest,
     app_state: web::Data<AppState>,
     payload: web::Json<GraphQLRequest>,
 ) -> impl Responder {
-    let server_ctx = app_state.0.lock().unwrap();
-    let mut pg_client = server_ctx.get_connection_pool().get().unwrap();
+    // NOTE: the server context isn't held on to while the request is authenticated
+    let auth_config = app_state.0.lock().unwrap().get_auth_config();
+    let session_variables = match auth_config.authenticate(req.headers()).await {
+        Ok(session_variables) => session_variables,
+        Err(e) => return GraphQLResponse::Error(e.to_graphql_errors()),
+    };
 
-    match graphql_parser::parse_query::<&str>(&payload.query) {
-        // NOTE: We only execute the first query/mutation/subscription that
-        // gets matched/parsed. Similar to what Hasura does
-        Ok(q) => match &q.definitions[0] {
-            graphql_parser::query::Definition::Fragment(_) => {
-                GraphQLResponse::error(String::from("Fragments are not supported"))
-            }
-            graphql_parser::query::Definition::Operation(op) => match op {
-                graphql_parser::query::OperationDefinition::Mutation(_) => {
-                    GraphQLResponse::error(String::from("Mutations are not supported"))
-                }
-                graphql_parser::query::OperationDefinition::Subscription(_) => {
-                    GraphQLResponse::error(String::from("Subscriptions are not supported"))
-                }
-                graphql_parser::query::OperationDefinition::Query(qry) => {
-                    return map_either(
-                        GraphQLResponse::error,
-                        GraphQLResponse::data,
-                        fetch_result_from_query_fields(
-                            &qry.selection_set,
-                            &mut pg_client,
-                            server_ctx.get_metadata(),
-                        ),
-                    );
-                }
-                graphql_parser::query::OperationDefinition::SelectionSet(sel_set) => {
-                    return map_either(
-                        GraphQLResponse::error,
-                        GraphQLResponse::data,
-                        fetch_result_from_query_fields(
-                            sel_set,
-                            &mut pg_client,
-                            server_ctx.get_metadata(),
-                        ),
-                    );
-                }
-            },
-        },
-        Err(e) => GraphQLResponse::error(e.to_string()),
+    // NOTE: nor is it held on to while the operation runs
+    let (pg_pool, metadata, permissions_mode) = operation_context(&app_state);
+    let mut pg_client = match pooled_client(&pg_pool) {
+        Ok(pg_client) => pg_client,
+        Err(e) => return GraphQLResponse::error(e.to_string()),
+    };
+
+    match parse_graphql_operation(&payload, metadata.naming_convention()) {
+        Ok(GraphQLOperation::Query(operation_fields)) => map_either(
+            GraphQLResponse::error,
+            GraphQLResponse::data,
+            fetch_result_from_operation_fields(
+                &mut pg_client,
+                &operation_fields,
+                &metadata,
+                &session_variables,
+                permissions_mode,
+            ),
+        ),
+        Ok(GraphQLOperation::Mutation(mutation_fields)) => map_either(
+            GraphQLResponse::error,
+            GraphQLResponse::data,
+            run_mutation_fields(
+                &mut pg_client,
+                &mutation_fields,
+                &metadata,
+                &session_variables,
+                permissions_mode,
+            ),
+        ),
+        Ok(GraphQLOperation::Subscription(_)) => GraphQLResponse::error(String::from(
+            "Subscriptions are only supported over WebSockets and Server-Sent Events",
+        )),
+        Err(e) => GraphQLResponse::error(e),
     }
 }
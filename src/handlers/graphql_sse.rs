@@ -0,0 +1,108 @@
+use actix_web::{dev::RequestHead, http::header, web, HttpRequest, HttpResponse};
+use futures::channel::mpsc::unbounded;
+use futures::{stream, StreamExt};
+
+use crate::context::AppState;
+use crate::graphql::{
+    parse_graphql_operation, run_blocking, run_mutation_operation, run_query_operation,
+    GraphQLOperation, GraphQLRequest,
+};
+use crate::subscriptions::{to_execution_result, LiveQueryPoller};
+
+pub const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+
+// NOTE: with the "distinct connections" mode of the GraphQL-over-SSE protocol, every
+// operation gets a connection of its own, so there's no need for an operation id
+const SSE_OPERATION_ID: &str = "sse";
+
+/// Routes the requests that accept an event stream to the `graphql_sse_handler`
+pub fn accepts_event_stream(req: &RequestHead) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(EVENT_STREAM_CONTENT_TYPE))
+}
+
+fn next_event(payload: &serde_json::Value) -> web::Bytes {
+    web::Bytes::from(format!("event: next\ndata: {}\n\n", payload))
+}
+
+fn complete_event() -> web::Bytes {
+    web::Bytes::from_static(b"event: complete\ndata:\n\n")
+}
+
+/// Serves GraphQL operations over Server-Sent Events, as per the "distinct connections"
+/// mode of the GraphQL-over-SSE protocol. Queries get a single `next` event followed by a
+/// `complete` event, while subscriptions get a `next` event for every new result until
+/// the client goes away.
+pub async fn graphql_sse_handler(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    poller: web::Data<LiveQueryPoller>,
+    payload: web::Json<GraphQLRequest>,
+) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(EVENT_STREAM_CONTENT_TYPE)
+        .header(header::CACHE_CONTROL, "no-cache");
+
+    let (naming_convention, auth_config) = {
+        let server_ctx = app_state.0.lock().unwrap();
+        (
+            server_ctx.get_metadata().naming_convention(),
+            server_ctx.get_auth_config(),
+        )
+    };
+    let session_variables = match auth_config.authenticate(req.headers()).await {
+        Ok(session_variables) => session_variables,
+        Err(err) => {
+            return response.streaming(stream::iter(vec![
+                Ok::<_, actix_web::Error>(next_event(&err.to_graphql_errors())),
+                Ok(complete_event()),
+            ]))
+        }
+    };
+    match parse_graphql_operation(&payload, naming_convention) {
+        // NOTE: the subscriber is dropped by the poller once the response stream
+        // (and hence the receiver) is dropped, i.e. when the client disconnects
+        Ok(GraphQLOperation::Subscription(operation_fields)) => {
+            let (sender, receiver) = unbounded();
+            poller.add_subscriber(
+                String::from(SSE_OPERATION_ID),
+                operation_fields,
+                session_variables,
+                sender,
+            );
+
+            response.streaming(receiver.map(|event| {
+                Ok::<_, actix_web::Error>(next_event(&to_execution_result(event.result)))
+            }))
+        }
+        Ok(GraphQLOperation::Query(operation_fields)) => {
+            let result = run_blocking(move || {
+                run_query_operation(&app_state, &operation_fields, &session_variables)
+            })
+            .await;
+
+            response.streaming(stream::iter(vec![
+                Ok::<_, actix_web::Error>(next_event(&to_execution_result(result))),
+                Ok(complete_event()),
+            ]))
+        }
+        Ok(GraphQLOperation::Mutation(mutation_fields)) => {
+            let result = run_blocking(move || {
+                run_mutation_operation(&app_state, &mutation_fields, &session_variables)
+            })
+            .await;
+
+            response.streaming(stream::iter(vec![
+                Ok::<_, actix_web::Error>(next_event(&to_execution_result(result))),
+                Ok(complete_event()),
+            ]))
+        }
+        Err(err) => response.streaming(stream::iter(vec![
+            Ok::<_, actix_web::Error>(next_event(&to_execution_result(Err(err)))),
+            Ok(complete_event()),
+        ])),
+    }
+}
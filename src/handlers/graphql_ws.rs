@@ -0,0 +1,582 @@
+use actix::{Actor, ActorContext, ActorFuture, AsyncContext, StreamHandler, WrapFuture};
+use actix_web::http::{header, HeaderMap, HeaderName, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::context::AppState;
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::graphql::{
+    parse_graphql_operation, run_blocking, run_mutation_operation, run_query_operation,
+    GraphQLOperation, GraphQLRequest,
+};
+use crate::session::SessionVariables;
+use crate::subscriptions::{
+    to_execution_result, LiveQueryEvent, LiveQueryPoller, LiveQueryResult, SubscriberId,
+};
+
+pub const GRAPHQL_TRANSPORT_WS_PROTOCOL: &str = "graphql-transport-ws";
+// NOTE: this is the protocol of Apollo's (now legacy) `subscriptions-transport-ws`
+pub const GRAPHQL_WS_PROTOCOL: &str = "graphql-ws";
+
+// NOTE: the client has to send a `connection_init` message within this duration
+const CONNECTION_INIT_TIMEOUT: Duration = Duration::from_secs(3);
+// NOTE: the interval at which `ka` messages are sent with the `graphql-ws` protocol
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// NOTE: These are the close codes as defined by the `graphql-transport-ws` protocol
+const CLOSE_BAD_REQUEST: u16 = 4400;
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+const CLOSE_FORBIDDEN: u16 = 4403;
+const CLOSE_CONNECTION_INIT_TIMEOUT: u16 = 4408;
+const CLOSE_SUBSCRIBER_ALREADY_EXISTS: u16 = 4409;
+const CLOSE_TOO_MANY_INIT_REQUESTS: u16 = 4429;
+
+/// The sub-protocols that the WebSocket transport can speak
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WsProtocol {
+    GraphQLTransportWs,
+    GraphQLWs,
+}
+
+impl WsProtocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WsProtocol::GraphQLTransportWs => GRAPHQL_TRANSPORT_WS_PROTOCOL,
+            WsProtocol::GraphQLWs => GRAPHQL_WS_PROTOCOL,
+        }
+    }
+
+    /// Picks the first of the protocols in the `Sec-WebSocket-Protocol` header that
+    /// we support, defaulting to `graphql-transport-ws` when there's none
+    fn negotiate(req: &HttpRequest) -> WsProtocol {
+        req.headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|protocols| protocols.to_str().ok())
+            .and_then(|protocols| {
+                protocols
+                    .split(',')
+                    .find_map(|protocol| match protocol.trim() {
+                        GRAPHQL_TRANSPORT_WS_PROTOCOL => Some(WsProtocol::GraphQLTransportWs),
+                        GRAPHQL_WS_PROTOCOL => Some(WsProtocol::GraphQLWs),
+                        _ => None,
+                    })
+            })
+            .unwrap_or(WsProtocol::GraphQLTransportWs)
+    }
+}
+
+/// The `payload` of a `connection_init` message, which may carry the headers that the
+/// connection is authenticated with in place of the headers of the upgrade request
+#[derive(Deserialize, Debug)]
+struct ConnectionInitPayload {
+    #[serde(default)]
+    headers: Option<HashMap<String, String>>,
+}
+
+// ----- `graphql-transport-ws` messages
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TransportWsClientMessage {
+    ConnectionInit {
+        #[serde(default)]
+        payload: Option<ConnectionInitPayload>,
+    },
+    Ping,
+    Pong,
+    Subscribe {
+        id: String,
+        payload: GraphQLRequest,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TransportWsServerMessage {
+    ConnectionAck,
+    Pong,
+    Next {
+        id: String,
+        payload: serde_json::Value,
+    },
+    Error {
+        id: String,
+        payload: Vec<serde_json::Value>,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+// ----- `graphql-ws` (`subscriptions-transport-ws`) messages
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum GraphQLWsClientMessage {
+    ConnectionInit {
+        #[serde(default)]
+        payload: Option<ConnectionInitPayload>,
+    },
+    Start {
+        id: String,
+        payload: GraphQLRequest,
+    },
+    Stop {
+        id: String,
+    },
+    ConnectionTerminate,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum GraphQLWsServerMessage {
+    ConnectionAck,
+    ConnectionError {
+        payload: serde_json::Value,
+    },
+    Ka,
+    Data {
+        id: String,
+        payload: serde_json::Value,
+    },
+    Error {
+        id: String,
+        payload: serde_json::Value,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+/// A single WebSocket connection speaking either of the `WsProtocol`s
+pub struct GraphQLWsSession {
+    app_state: web::Data<AppState>,
+    poller: web::Data<LiveQueryPoller>,
+    protocol: WsProtocol,
+    // NOTE: the connection is authenticated with these when `connection_init` has no headers
+    upgrade_headers: HeaderMap,
+    // NOTE: every operation on the connection runs with the session that `connection_init`
+    // resolved, and there's none until then
+    session_variables: Option<SessionVariables>,
+    // NOTE: maps the operation ids given by the client to their subscribers
+    operations: HashMap<String, SubscriberId>,
+    sender: UnboundedSender<LiveQueryEvent>,
+    receiver: Option<UnboundedReceiver<LiveQueryEvent>>,
+}
+
+impl GraphQLWsSession {
+    pub fn new(
+        app_state: web::Data<AppState>,
+        poller: web::Data<LiveQueryPoller>,
+        protocol: WsProtocol,
+        upgrade_headers: HeaderMap,
+    ) -> Self {
+        let (sender, receiver) = unbounded();
+
+        GraphQLWsSession {
+            app_state,
+            poller,
+            protocol,
+            upgrade_headers,
+            session_variables: None,
+            operations: HashMap::new(),
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+
+    fn send<T>(&self, ctx: &mut ws::WebsocketContext<Self>, message: T)
+    where
+        T: Serialize + std::fmt::Debug,
+    {
+        match serde_json::to_string(&message) {
+            Ok(text) => ctx.text(text),
+            Err(err) => warn!("Failed to serialize {:?}: {}", message, err),
+        }
+    }
+
+    fn close(&self, ctx: &mut ws::WebsocketContext<Self>, code: u16, reason: &str) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Other(code),
+            description: Some(reason.to_string()),
+        }));
+        ctx.stop();
+    }
+
+    fn send_result(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: String,
+        result: LiveQueryResult,
+    ) {
+        let payload = to_execution_result(result);
+
+        match self.protocol {
+            WsProtocol::GraphQLTransportWs => {
+                self.send(ctx, TransportWsServerMessage::Next { id, payload })
+            }
+            WsProtocol::GraphQLWs => self.send(ctx, GraphQLWsServerMessage::Data { id, payload }),
+        }
+    }
+
+    fn send_error(&self, ctx: &mut ws::WebsocketContext<Self>, id: String, message: String) {
+        match self.protocol {
+            WsProtocol::GraphQLTransportWs => self.send(
+                ctx,
+                TransportWsServerMessage::Error {
+                    id,
+                    payload: vec![json!({ "message": message })],
+                },
+            ),
+            WsProtocol::GraphQLWs => self.send(
+                ctx,
+                GraphQLWsServerMessage::Error {
+                    id,
+                    payload: json!({ "message": message }),
+                },
+            ),
+        }
+    }
+
+    fn send_complete(&self, ctx: &mut ws::WebsocketContext<Self>, id: String) {
+        match self.protocol {
+            WsProtocol::GraphQLTransportWs => {
+                self.send(ctx, TransportWsServerMessage::Complete { id })
+            }
+            WsProtocol::GraphQLWs => self.send(ctx, GraphQLWsServerMessage::Complete { id }),
+        }
+    }
+
+    fn handle_transport_ws_message(&mut self, ctx: &mut ws::WebsocketContext<Self>, text: &str) {
+        let message: TransportWsClientMessage = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(err) => {
+                return self.close(ctx, CLOSE_BAD_REQUEST, &format!("Invalid message: {}", err));
+            }
+        };
+
+        match message {
+            TransportWsClientMessage::ConnectionInit { payload } => {
+                if self.session_variables.is_some() {
+                    return self.close(
+                        ctx,
+                        CLOSE_TOO_MANY_INIT_REQUESTS,
+                        "Too many initialisation requests",
+                    );
+                }
+                self.authenticate(ctx, payload);
+            }
+            TransportWsClientMessage::Ping => self.send(ctx, TransportWsServerMessage::Pong),
+            TransportWsClientMessage::Pong => (),
+            TransportWsClientMessage::Subscribe { id, payload } => {
+                let session_variables = match &self.session_variables {
+                    Some(session_variables) => session_variables.clone(),
+                    None => return self.close(ctx, CLOSE_UNAUTHORIZED, "Unauthorized"),
+                };
+                if self.operations.contains_key(&id) {
+                    return self.close(
+                        ctx,
+                        CLOSE_SUBSCRIBER_ALREADY_EXISTS,
+                        &format!("Subscriber for {} already exists", id),
+                    );
+                }
+                self.subscribe(ctx, id, payload, session_variables);
+            }
+            TransportWsClientMessage::Complete { id } => self.unsubscribe(&id),
+        }
+    }
+
+    fn handle_graphql_ws_message(&mut self, ctx: &mut ws::WebsocketContext<Self>, text: &str) {
+        let message: GraphQLWsClientMessage = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(err) => {
+                return self.send(
+                    ctx,
+                    GraphQLWsServerMessage::ConnectionError {
+                        payload: json!({ "message": format!("Invalid message: {}", err) }),
+                    },
+                );
+            }
+        };
+
+        match message {
+            GraphQLWsClientMessage::ConnectionInit { payload } => self.authenticate(ctx, payload),
+            GraphQLWsClientMessage::Start { id, payload } => {
+                let session_variables = match &self.session_variables {
+                    Some(session_variables) => session_variables.clone(),
+                    None => {
+                        return self.send(
+                            ctx,
+                            GraphQLWsServerMessage::ConnectionError {
+                                payload: json!({ "message": "Connection not initialised" }),
+                            },
+                        )
+                    }
+                };
+                if self.operations.contains_key(&id) {
+                    return self.send_error(
+                        ctx,
+                        id.clone(),
+                        format!("An operation with id {} already exists", id),
+                    );
+                }
+                self.subscribe(ctx, id, payload, session_variables);
+            }
+            GraphQLWsClientMessage::Stop { id } => {
+                self.unsubscribe(&id);
+                self.send_complete(ctx, id);
+            }
+            GraphQLWsClientMessage::ConnectionTerminate => {
+                ctx.close(None);
+                ctx.stop();
+            }
+        }
+    }
+
+    /// Resolves the session of the connection from the headers in the payload of
+    /// `connection_init`, or from the headers of the upgrade request when it has none.
+    /// The other messages wait for the session, which may have to come from the webhook
+    fn authenticate(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        payload: Option<ConnectionInitPayload>,
+    ) {
+        let headers = match payload.and_then(|payload| payload.headers) {
+            Some(headers) => match headers_from_payload(&headers) {
+                Ok(headers) => headers,
+                Err(err) => return self.reject_connection(ctx, err),
+            },
+            None => self.upgrade_headers.clone(),
+        };
+
+        let auth_config = self.app_state.0.lock().unwrap().get_auth_config();
+        let authentication = async move { auth_config.authenticate(&headers).await };
+        ctx.wait(
+            authentication
+                .into_actor(self)
+                .map(|result, session, ctx| match result {
+                    Ok(session_variables) => session.accept_connection(ctx, session_variables),
+                    Err(err) => session.reject_connection(ctx, err),
+                }),
+        );
+    }
+
+    fn accept_connection(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        session_variables: SessionVariables,
+    ) {
+        let is_first_init = self.session_variables.is_none();
+        self.session_variables = Some(session_variables);
+
+        match self.protocol {
+            WsProtocol::GraphQLTransportWs => {
+                self.send(ctx, TransportWsServerMessage::ConnectionAck)
+            }
+            WsProtocol::GraphQLWs => {
+                self.send(ctx, GraphQLWsServerMessage::ConnectionAck);
+                self.send(ctx, GraphQLWsServerMessage::Ka);
+
+                if is_first_init {
+                    ctx.run_interval(KEEP_ALIVE_INTERVAL, |session, ctx| {
+                        session.send(ctx, GraphQLWsServerMessage::Ka)
+                    });
+                }
+            }
+        }
+    }
+
+    fn reject_connection(&self, ctx: &mut ws::WebsocketContext<Self>, err: GQLRSError) {
+        match self.protocol {
+            WsProtocol::GraphQLTransportWs => self.close(ctx, CLOSE_FORBIDDEN, "Forbidden"),
+            WsProtocol::GraphQLWs => {
+                self.send(
+                    ctx,
+                    GraphQLWsServerMessage::ConnectionError {
+                        payload: err.to_graphql_errors()["errors"][0].clone(),
+                    },
+                );
+                ctx.close(None);
+                ctx.stop();
+            }
+        }
+    }
+
+    fn subscribe(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: String,
+        request: GraphQLRequest,
+        session_variables: SessionVariables,
+    ) {
+        let naming_convention = self
+            .app_state
+            .0
+            .lock()
+            .unwrap()
+            .get_metadata()
+            .naming_convention();
+        match parse_graphql_operation(&request, naming_convention) {
+            Ok(GraphQLOperation::Subscription(operation_fields)) => {
+                let subscriber_id = self.poller.add_subscriber(
+                    id.clone(),
+                    operation_fields,
+                    session_variables,
+                    self.sender.clone(),
+                );
+                self.operations.insert(id, subscriber_id);
+            }
+            // NOTE: queries are run just the once, and completed right away
+            Ok(GraphQLOperation::Query(operation_fields)) => {
+                let app_state = self.app_state.clone();
+                self.run_once(
+                    ctx,
+                    id,
+                    run_blocking(move || {
+                        run_query_operation(&app_state, &operation_fields, &session_variables)
+                    }),
+                );
+            }
+            // NOTE: and so are mutations
+            Ok(GraphQLOperation::Mutation(mutation_fields)) => {
+                let app_state = self.app_state.clone();
+                self.run_once(
+                    ctx,
+                    id,
+                    run_blocking(move || {
+                        run_mutation_operation(&app_state, &mutation_fields, &session_variables)
+                    }),
+                );
+            }
+            Err(err) => self.send_error(ctx, id, err),
+        }
+    }
+
+    /// Sends the result of an operation that's run just the once, and completes it. The
+    /// operation runs in the background, so that the connection carries on with the other
+    /// messages (like the ones that complete the subscriptions) in the meantime
+    fn run_once<F>(&self, ctx: &mut ws::WebsocketContext<Self>, id: String, operation: F)
+    where
+        F: Future<Output = LiveQueryResult> + 'static,
+    {
+        ctx.spawn(operation.into_actor(self).map(|result, session, ctx| {
+            session.send_result(ctx, id.clone(), result);
+            session.send_complete(ctx, id);
+        }));
+    }
+
+    fn unsubscribe(&mut self, id: &str) {
+        if let Some(subscriber_id) = self.operations.remove(id) {
+            self.poller.remove_subscriber(subscriber_id);
+        }
+    }
+}
+
+/// The headers in the payload of `connection_init`, as the headers of a request
+fn headers_from_payload(headers: &HashMap<String, String>) -> Result<HeaderMap, GQLRSError> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let header_name = HeaderName::from_bytes(name.to_lowercase().as_bytes());
+        let header_value = HeaderValue::from_str(value);
+        match (header_name, header_value) {
+            (Ok(header_name), Ok(header_value)) => header_map.append(header_name, header_value),
+            _ => {
+                return Err(GQLRSError::new(GQLRSErrorType::InvalidHeaders(format!(
+                    "The header `{}` in the payload of `connection_init` is invalid",
+                    name
+                ))))
+            }
+        }
+    }
+
+    Ok(header_map)
+}
+
+impl Actor for GraphQLWsSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(receiver) = self.receiver.take() {
+            ctx.add_stream(receiver);
+        }
+
+        ctx.run_later(CONNECTION_INIT_TIMEOUT, |session, ctx| {
+            if session.session_variables.is_none() {
+                session.close(
+                    ctx,
+                    CLOSE_CONNECTION_INIT_TIMEOUT,
+                    "Connection initialisation timeout",
+                );
+            }
+        });
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        for subscriber_id in self.operations.values() {
+            self.poller.remove_subscriber(*subscriber_id);
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GraphQLWsSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Text(text)) => match self.protocol {
+                WsProtocol::GraphQLTransportWs => self.handle_transport_ws_message(ctx, &text),
+                WsProtocol::GraphQLWs => self.handle_graphql_ws_message(ctx, &text),
+            },
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(ws::Message::Binary(_)) => {
+                self.close(ctx, CLOSE_BAD_REQUEST, "Binary messages are not supported")
+            }
+            Ok(_) => (),
+            Err(err) => {
+                debug!("WebSocket protocol error: {}", err);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl StreamHandler<LiveQueryEvent> for GraphQLWsSession {
+    fn handle(&mut self, event: LiveQueryEvent, ctx: &mut Self::Context) {
+        // NOTE: the operation might've been completed in the meantime
+        if !self.operations.contains_key(&event.operation_id) {
+            return;
+        }
+
+        self.send_result(ctx, event.operation_id, event.result);
+    }
+}
+
+pub async fn graphql_ws_handler(
+    app_state: web::Data<AppState>,
+    poller: web::Data<LiveQueryPoller>,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let protocol = WsProtocol::negotiate(&req);
+
+    // NOTE: the connection is only authenticated by `connection_init`, whose payload
+    // may have the headers that the client couldn't set on the upgrade request
+    ws::start_with_protocols(
+        GraphQLWsSession::new(app_state, poller, protocol, req.headers().clone()),
+        &[protocol.name()],
+        &req,
+        stream,
+    )
+}
@@ -1,24 +1,280 @@
 use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Responder};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use crate::context::AppState;
-use crate::error::GQLRSError;
-use crate::metadata::{Metadata, QualifiedTable};
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::metadata::{DbTable, Metadata, QualifiedTable, SourceCustomization, TableConfiguration};
+use crate::permissions::{
+    DeletePermission, InheritedRole, InsertPermission, Permission, SelectPermission,
+    UpdatePermission,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type", content = "args", rename_all = "snake_case")]
 pub enum MetadataRequestBody {
     TrackTable(QualifiedTable),
     UntrackTable(QualifiedTable),
-    // NOTE: args will be `null` for `export_metadata`
+    // NOTE: args will be `null` for `export_metadata`, `reload_metadata`,
+    // `get_inconsistent_metadata` and `get_untracked_tables`
     ExportMetadata,
     ImportMetadata(Metadata),
+    ReloadMetadata,
+    GetInconsistentMetadata,
+    // NOTE: the changes in a `bulk` request are either all applied, or none of them are
+    Bulk(Vec<MetadataRequestBody>),
+    GetUntrackedTables,
+    TrackTables(TrackTablesArgs),
+    TrackSchema(TrackSchemaArgs),
+    SetTableCustomization(Box<SetTableCustomizationArgs>),
+    UpdateSource(UpdateSourceArgs),
+    CreateSelectPermission(CreatePermissionArgs<SelectPermission>),
+    DropSelectPermission(DropPermissionArgs),
+    CreateInsertPermission(CreatePermissionArgs<InsertPermission>),
+    DropInsertPermission(DropPermissionArgs),
+    CreateUpdatePermission(CreatePermissionArgs<UpdatePermission>),
+    DropUpdatePermission(DropPermissionArgs),
+    CreateDeletePermission(CreatePermissionArgs<DeletePermission>),
+    DropDeletePermission(DropPermissionArgs),
+    AddInheritedRole(InheritedRole),
+    DropInheritedRole(DropInheritedRoleArgs),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrackTablesArgs {
+    pub tables: Vec<QualifiedTable>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrackSchemaArgs {
+    pub schema: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CreatePermissionArgs<P> {
+    #[serde(flatten)]
+    pub table: QualifiedTable,
+    pub role: String,
+    pub permission: P,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DropPermissionArgs {
+    #[serde(flatten)]
+    pub table: QualifiedTable,
+    pub role: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DropInheritedRoleArgs {
+    pub role_name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateSourceArgs {
+    pub name: String,
+    #[serde(default)]
+    pub customization: SourceCustomization,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SetTableCustomizationArgs {
+    #[serde(flatten)]
+    pub table: QualifiedTable,
+    // NOTE: the configuration replaces the previous one, so an empty
+    // configuration removes the customization of the table
+    #[serde(default)]
+    pub configuration: TableConfiguration,
+}
+
+/// Groups the untracked tables of the database by their schemas
+fn untracked_tables_by_schema(
+    metadata: &Metadata,
+    db_tables: &[DbTable],
+) -> IndexMap<String, Vec<String>> {
+    let mut untracked_tables: IndexMap<String, Vec<String>> = IndexMap::new();
+
+    for table in metadata.untracked_tables(db_tables) {
+        untracked_tables
+            .entry(table.schema_name.clone())
+            .or_default()
+            .push(table.table_name.clone());
+    }
+
+    untracked_tables
+}
+
+fn create_permission<P: Permission>(
+    metadata: &mut Metadata,
+    args: CreatePermissionArgs<P>,
+) -> Result<Vec<String>, GQLRSError> {
+    let message = format!(
+        "The {} permission of the role `{}` on {} has been created!",
+        P::KIND,
+        args.role,
+        args.table
+    );
+    metadata
+        .create_permission(args.table, args.role, args.permission)
+        .map(|_| vec![message])
+}
+
+fn drop_permission<P: Permission>(
+    metadata: &mut Metadata,
+    args: DropPermissionArgs,
+) -> Result<Vec<String>, GQLRSError> {
+    let message = format!(
+        "The {} permission of the role `{}` on {} has been dropped!",
+        P::KIND,
+        args.role,
+        args.table
+    );
+    metadata
+        .drop_permission::<P>(args.table, args.role)
+        .map(|_| vec![message])
+}
+
+/// Applies a change to the metadata, returning the success message(s) of the change
+fn apply_metadata_change(
+    metadata: &mut Metadata,
+    db_tables: &[DbTable],
+    body: MetadataRequestBody,
+) -> Result<Vec<String>, GQLRSError> {
+    match body {
+        MetadataRequestBody::TrackTable(table) => {
+            let message = format!("{} is now being tracked!", table);
+            metadata.track_table(table).map(|_| vec![message])
+        }
+        MetadataRequestBody::UntrackTable(table) => {
+            let message = format!("{} has now been un-tracked!", table);
+            metadata.untrack_table(table).map(|_| vec![message])
+        }
+        MetadataRequestBody::ImportMetadata(md) => metadata
+            .set_metadata(&md)
+            .map(|_| vec!["Imported metadata successfully!".to_string()]),
+        MetadataRequestBody::Bulk(bodies) => {
+            let mut messages = Vec::new();
+            for body in bodies {
+                messages.extend(apply_metadata_change(metadata, db_tables, body)?);
+            }
+            Ok(messages)
+        }
+        MetadataRequestBody::TrackTables(args) => {
+            let mut messages = Vec::new();
+            for table in args.tables {
+                messages.extend(apply_metadata_change(
+                    metadata,
+                    db_tables,
+                    MetadataRequestBody::TrackTable(table),
+                )?);
+            }
+            Ok(messages)
+        }
+        // NOTE: this tracks every table of the schema that isn't tracked already
+        MetadataRequestBody::TrackSchema(args) => {
+            let schema_tables: Vec<QualifiedTable> = metadata
+                .untracked_tables(db_tables)
+                .into_iter()
+                .filter(|table| table.schema_name == args.schema)
+                .cloned()
+                .collect();
+
+            if schema_tables.is_empty() {
+                return Ok(vec![format!(
+                    "There are no untracked tables in the schema {}",
+                    args.schema
+                )]);
+            }
+
+            apply_metadata_change(
+                metadata,
+                db_tables,
+                MetadataRequestBody::TrackTables(TrackTablesArgs {
+                    tables: schema_tables,
+                }),
+            )
+        }
+        MetadataRequestBody::SetTableCustomization(args) => {
+            let SetTableCustomizationArgs {
+                table,
+                configuration,
+            } = *args;
+            let message = format!("The customization of {} has been set!", table);
+            metadata
+                .set_table_customization(table, configuration)
+                .map(|_| vec![message])
+        }
+        MetadataRequestBody::UpdateSource(args) => {
+            if args.name != metadata.source_name {
+                return Err(GQLRSError::new(GQLRSErrorType::InvalidInput(format!(
+                    "The source {} doesn't exist",
+                    args.name
+                ))));
+            }
+
+            let message = format!("The source {} has been updated!", args.name);
+            metadata
+                .set_source_customization(args.customization)
+                .map(|_| vec![message])
+        }
+        MetadataRequestBody::CreateSelectPermission(args) => create_permission(metadata, args),
+        MetadataRequestBody::DropSelectPermission(args) => {
+            drop_permission::<SelectPermission>(metadata, args)
+        }
+        MetadataRequestBody::CreateInsertPermission(args) => create_permission(metadata, args),
+        MetadataRequestBody::DropInsertPermission(args) => {
+            drop_permission::<InsertPermission>(metadata, args)
+        }
+        MetadataRequestBody::CreateUpdatePermission(args) => create_permission(metadata, args),
+        MetadataRequestBody::DropUpdatePermission(args) => {
+            drop_permission::<UpdatePermission>(metadata, args)
+        }
+        MetadataRequestBody::CreateDeletePermission(args) => create_permission(metadata, args),
+        MetadataRequestBody::DropDeletePermission(args) => {
+            drop_permission::<DeletePermission>(metadata, args)
+        }
+        MetadataRequestBody::AddInheritedRole(inherited_role) => {
+            let message = format!(
+                "The inherited role `{}` has been added!",
+                inherited_role.role_name
+            );
+            metadata
+                .add_inherited_role(inherited_role)
+                .map(|_| vec![message])
+        }
+        MetadataRequestBody::DropInheritedRole(args) => {
+            let message = format!("The inherited role `{}` has been dropped!", args.role_name);
+            metadata
+                .drop_inherited_role(&args.role_name)
+                .map(|_| vec![message])
+        }
+        MetadataRequestBody::ExportMetadata
+        | MetadataRequestBody::ReloadMetadata
+        | MetadataRequestBody::GetInconsistentMetadata
+        | MetadataRequestBody::GetUntrackedTables => {
+            Err(GQLRSError::new(GQLRSErrorType::InvalidInput(String::from(
+                "Only the requests that change the metadata can be a part of a `bulk` request",
+            ))))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetadataRequest {
+    #[serde(flatten)]
+    pub body: MetadataRequestBody,
+    // NOTE: when given, the request is only served if the metadata is still at
+    // this version, otherwise it's rejected with a conflict
+    pub resource_version: Option<u64>,
+    // NOTE: by default, changes that leave the metadata inconsistent with the
+    // database are rejected, but they can be kept (and reported) with this set
+    #[serde(default)]
+    pub allow_inconsistent_metadata: bool,
 }
 
 #[derive(Serialize, Debug, Clone)]
-pub enum MetadataResponse<T = Metadata> {
-    // NOTE: `T` is `Metadata` temporarily for `export_metadata` request
+pub enum MetadataResponse<T = serde_json::Value> {
     Success(String),
     Data(T),
     Error(GQLRSError),
@@ -34,7 +290,12 @@ where
     fn respond_to(self, _: &HttpRequest) -> Self::Future {
         match self {
             MetadataResponse::Error(err_resp) => {
-                HttpResponse::build(StatusCode::BAD_REQUEST).json(err_resp)
+                let status_code = match err_resp.kind {
+                    GQLRSErrorType::ResourceVersionConflict(_) => StatusCode::CONFLICT,
+                    GQLRSErrorType::AccessDenied(_) => StatusCode::UNAUTHORIZED,
+                    _ => StatusCode::BAD_REQUEST,
+                };
+                HttpResponse::build(status_code).json(err_resp)
             }
             MetadataResponse::Success(msg) => {
                 HttpResponse::Ok().json(json!({"success": true, "message": msg}))
@@ -45,36 +306,78 @@ where
 }
 
 pub async fn metadata_handler(
+    req: HttpRequest,
     app_state: web::Data<AppState>,
-    payload: web::Json<MetadataRequestBody>,
+    payload: web::Json<MetadataRequest>,
 ) -> impl Responder {
     let mut server_ctx = app_state.0.lock().unwrap();
+    let request = payload.into_inner();
 
-    match payload.into_inner() {
-        MetadataRequestBody::TrackTable(table) => {
-            match (*server_ctx).metadata_track_table(table.clone()) {
-                Ok(_) => MetadataResponse::Success(format!(
-                    "{} is now being tracked!",
-                    table.to_string()
-                )),
-                Err(err) => MetadataResponse::Error(err),
+    if let Err(err) = (*server_ctx)
+        .get_auth_config()
+        .check_admin_secret(req.headers())
+    {
+        return MetadataResponse::Error(err);
+    }
+
+    if let Err(err) = (*server_ctx).check_resource_version(request.resource_version) {
+        return MetadataResponse::Error(err);
+    }
+
+    match request.body {
+        MetadataRequestBody::ExportMetadata => {
+            MetadataResponse::Data(json!((*server_ctx).get_metadata()))
+        }
+        MetadataRequestBody::ReloadMetadata => match (*server_ctx).reload_metadata() {
+            Ok(_) => {
+                let inconsistent_objects = (*server_ctx).get_inconsistent_objects();
+                MetadataResponse::Data(json!({
+                    "message": "success",
+                    "is_consistent": inconsistent_objects.is_empty(),
+                    "inconsistent_objects": inconsistent_objects,
+                }))
             }
+            Err(err) => MetadataResponse::Error(err),
+        },
+        MetadataRequestBody::GetInconsistentMetadata => {
+            let inconsistent_objects = (*server_ctx).get_inconsistent_objects();
+            MetadataResponse::Data(json!({
+                "is_consistent": inconsistent_objects.is_empty(),
+                "inconsistent_objects": inconsistent_objects,
+            }))
         }
-        MetadataRequestBody::UntrackTable(table) => {
-            match (*server_ctx).metadata_untrack_table(table.clone()) {
-                Ok(_) => MetadataResponse::Success(format!(
-                    "{} has now been un-tracked!",
-                    table.to_string()
-                )),
+        MetadataRequestBody::GetUntrackedTables => match (*server_ctx).get_db_tables() {
+            Ok(db_tables) => MetadataResponse::Data(json!(untracked_tables_by_schema(
+                (*server_ctx).get_metadata(),
+                &db_tables
+            ))),
+            Err(err) => MetadataResponse::Error(err),
+        },
+        body => {
+            let has_many_changes = matches!(
+                body,
+                MetadataRequestBody::Bulk(_)
+                    | MetadataRequestBody::TrackTables(_)
+                    | MetadataRequestBody::TrackSchema(_)
+            );
+            let mut messages = Vec::new();
+            let update_result = (*server_ctx).update_metadata(
+                |metadata, db_tables| {
+                    messages = apply_metadata_change(metadata, db_tables, body)?;
+                    Ok(())
+                },
+                request.allow_inconsistent_metadata,
+            );
+
+            match update_result {
+                // NOTE: the requests with many changes respond with the result of every change
+                Ok(_) if has_many_changes => MetadataResponse::Data(json!(messages
+                    .into_iter()
+                    .map(|message| json!({"success": true, "message": message}))
+                    .collect::<Vec<serde_json::Value>>())),
+                Ok(_) => MetadataResponse::Success(messages.join(" ")),
                 Err(err) => MetadataResponse::Error(err),
             }
         }
-        MetadataRequestBody::ExportMetadata => {
-            MetadataResponse::Data((*server_ctx).get_metadata().clone())
-        }
-        MetadataRequestBody::ImportMetadata(md) => {
-            (*server_ctx).replace_metadata(&md);
-            MetadataResponse::Success("Imported metadata successfully!".to_string())
-        }
     }
 }
@@ -0,0 +1,480 @@
+use actix_web::http::HeaderMap;
+use indexmap::IndexMap;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::auth::AUTHORIZATION_HEADER;
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::session::{is_session_variable, SessionVariables, ROLE_SESSION_VARIABLE};
+
+pub const DEFAULT_CLAIMS_NAMESPACE: &str = "https://hasura.io/jwt/claims";
+
+const BEARER_PREFIX: &str = "Bearer ";
+const ALLOWED_ROLES_CLAIM: &str = "x-hasura-allowed-roles";
+const DEFAULT_ROLE_CLAIM: &str = "x-hasura-default-role";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+}
+
+impl JwtAlgorithm {
+    fn algorithm(self) -> Algorithm {
+        match self {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+        }
+    }
+}
+
+/// The audience that the tokens have to be issued for, either one or any of many
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum JwtAudience {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// A claim of the claims map, which is either read off of the claims
+/// with a JSON path (like `$.user.id`), or is the same for every token
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ClaimsMapValue {
+    Path {
+        path: String,
+        #[serde(default)]
+        default: Option<Value>,
+    },
+    Literal(Value),
+}
+
+/// The configuration of the JWT mode, in the same shape as the `HASURA_GRAPHQL_JWT_SECRET`
+/// of the Hasura GraphQL Engine, e.g. `{"type": "HS256", "key": "..."}`.
+/// The key is either given right away, or as a (local) JWKS file with the `jwks_file`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    #[serde(rename = "type")]
+    pub algorithm: JwtAlgorithm,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+    #[serde(default)]
+    pub claims_namespace: Option<String>,
+    #[serde(default)]
+    pub claims_namespace_path: Option<String>,
+    #[serde(default)]
+    pub claims_map: Option<IndexMap<String, ClaimsMapValue>>,
+    #[serde(default)]
+    pub audience: Option<JwtAudience>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+}
+
+impl std::str::FromStr for JwtConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map_err(|err| format!("invalid JWT configuration: {}", err))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+}
+
+fn invalid_jwt(reason: String) -> GQLRSError {
+    GQLRSError::new(GQLRSErrorType::InvalidJwt(reason))
+}
+
+fn invalid_config(reason: &str) -> GQLRSError {
+    GQLRSError::new(GQLRSErrorType::InvalidInput(format!(
+        "invalid JWT configuration: {}",
+        reason
+    )))
+}
+
+/// Verifies the tokens of the requests, and turns their claims into sessions
+#[derive(Debug, Clone)]
+pub struct JwtAuth {
+    config: JwtConfig,
+    // NOTE: the keys are kept along with their `kid`, which the tokens pick them with
+    keys: Vec<(Option<String>, DecodingKey<'static>)>,
+    validation: Validation,
+}
+
+impl JwtAuth {
+    pub fn new(config: JwtConfig) -> Result<JwtAuth, GQLRSError> {
+        if config.claims_namespace.is_some() && config.claims_namespace_path.is_some() {
+            return Err(invalid_config(
+                "`claims_namespace` and `claims_namespace_path` can't both be set",
+            ));
+        }
+
+        let keys = match (&config.key, &config.jwks_file) {
+            (Some(key), None) => vec![(None, decoding_key(config.algorithm, key)?)],
+            (None, Some(jwks_file)) => jwks_keys(config.algorithm, jwks_file)?,
+            _ => {
+                return Err(invalid_config(
+                    "exactly one of `key` and `jwks_file` is needed",
+                ))
+            }
+        };
+
+        let mut validation = Validation::new(config.algorithm.algorithm());
+        // NOTE: `exp` is optional, so the tokens are only checked against it when they have one
+        validation.validate_exp = false;
+        validation.iss = config.issuer.clone();
+        match &config.audience {
+            Some(JwtAudience::One(audience)) => validation.set_audience(&[audience]),
+            Some(JwtAudience::Many(audiences)) => validation.set_audience(audiences),
+            None => {}
+        }
+
+        Ok(JwtAuth {
+            config,
+            keys,
+            validation,
+        })
+    }
+
+    /// Resolves the session of a request from the token in its `Authorization` header.
+    /// The request may pick any of the allowed roles of the token with the `x-hasura-role`
+    /// header, and otherwise runs as the default role of the token
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<SessionVariables, GQLRSError> {
+        let token = bearer_token(headers)?;
+        let claims = self.verified_claims(token)?;
+        let hasura_claims = self.hasura_claims(&claims)?;
+
+        let allowed_roles = match hasura_claims.get(ALLOWED_ROLES_CLAIM) {
+            Some(Value::Array(roles)) => roles
+                .iter()
+                .map(|role| role.as_str().map(String::from))
+                .collect::<Option<Vec<String>>>(),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            invalid_jwt(format!(
+                "`{}` should be a list of roles in the claims",
+                ALLOWED_ROLES_CLAIM
+            ))
+        })?;
+        let default_role = hasura_claims
+            .get(DEFAULT_ROLE_CLAIM)
+            .and_then(Value::as_str)
+            .ok_or_else(|| {
+                invalid_jwt(format!(
+                    "`{}` is missing from the claims",
+                    DEFAULT_ROLE_CLAIM
+                ))
+            })?;
+        if !allowed_roles.iter().any(|role| role == default_role) {
+            return Err(invalid_jwt(String::from(
+                "the default role is not one of the allowed roles",
+            )));
+        }
+
+        let role = match headers.get(ROLE_SESSION_VARIABLE) {
+            Some(role) => role.to_str().map_err(|_| {
+                GQLRSError::new(GQLRSErrorType::InvalidHeaders(format!(
+                    "The value of the session variable `{}` should be a visible ASCII string",
+                    ROLE_SESSION_VARIABLE
+                )))
+            })?,
+            None => default_role,
+        };
+        if !allowed_roles
+            .iter()
+            .any(|allowed_role| allowed_role == role)
+        {
+            return Err(GQLRSError::new(GQLRSErrorType::AccessDenied(format!(
+                "the requested role `{}` is not one of the allowed roles",
+                role
+            ))));
+        }
+
+        let mut session_variables = SessionVariables::default();
+        for (name, value) in hasura_claims.iter() {
+            let value = match value {
+                // NOTE: lists (like the allowed roles) are given to the database as array literals
+                Value::Array(values) => format!(
+                    "{{{}}}",
+                    values
+                        .iter()
+                        .map(claim_to_string)
+                        .collect::<Option<Vec<String>>>()
+                        .ok_or_else(|| invalid_claim(name))?
+                        .join(",")
+                ),
+                value => claim_to_string(value).ok_or_else(|| invalid_claim(name))?,
+            };
+            if name != DEFAULT_ROLE_CLAIM && is_session_variable(name) {
+                session_variables.insert(name, &value)?;
+            }
+        }
+        session_variables.insert(ROLE_SESSION_VARIABLE, role)?;
+
+        Ok(session_variables)
+    }
+
+    fn verified_claims(&self, token: &str) -> Result<Value, GQLRSError> {
+        let header = decode_header(token).map_err(|err| invalid_jwt(format!("{:?}", err)))?;
+
+        let key = match &header.kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|(key_id, _)| key_id.as_deref() == Some(kid.as_str()))
+                .or_else(|| self.keys.iter().find(|(key_id, _)| key_id.is_none())),
+            None => self.keys.first(),
+        }
+        .map(|(_, key)| key)
+        .ok_or_else(|| invalid_jwt(String::from("no key matches the `kid` of the token")))?;
+
+        let claims = decode::<Value>(token, key, &self.validation)
+            .map(|token_data| token_data.claims)
+            .map_err(|err| invalid_jwt(format!("{:?}", err.kind())))?;
+
+        // NOTE: `exp` is a NumericDate, which can have a fractional part
+        if let Some(exp) = claims.get("exp") {
+            let exp = exp
+                .as_f64()
+                .ok_or_else(|| invalid_jwt(String::from("`exp` should be a timestamp")))?
+                as u64;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or_default();
+            if exp < now.saturating_sub(self.validation.leeway) {
+                return Err(invalid_jwt(format!("{:?}", ErrorKind::ExpiredSignature)));
+            }
+        }
+
+        Ok(claims)
+    }
+
+    /// The `x-hasura-*` claims of the token, by way of the claims map when there's one
+    fn hasura_claims(&self, claims: &Value) -> Result<IndexMap<String, Value>, GQLRSError> {
+        if let Some(claims_map) = &self.config.claims_map {
+            let mut hasura_claims = IndexMap::new();
+            for (name, claims_map_value) in claims_map.iter() {
+                let value = match claims_map_value {
+                    ClaimsMapValue::Path { path, default } => json_path_lookup(claims, path)?
+                        .or(default.as_ref())
+                        .cloned()
+                        .ok_or_else(|| {
+                            invalid_jwt(format!("the claim at `{}` is missing", path))
+                        })?,
+                    ClaimsMapValue::Literal(value) => value.clone(),
+                };
+                hasura_claims.insert(name.to_lowercase(), value);
+            }
+            return Ok(hasura_claims);
+        }
+
+        let namespace = match &self.config.claims_namespace_path {
+            Some(path) => json_path_lookup(claims, path)?,
+            None => claims.get(
+                self.config
+                    .claims_namespace
+                    .as_deref()
+                    .unwrap_or(DEFAULT_CLAIMS_NAMESPACE),
+            ),
+        };
+        // NOTE: some providers can only issue string claims, so the namespace may be stringified JSON
+        let namespace = match namespace {
+            Some(Value::String(stringified)) => serde_json::from_str(stringified).ok(),
+            namespace => namespace.cloned(),
+        };
+
+        match namespace {
+            Some(Value::Object(namespace)) => Ok(namespace
+                .into_iter()
+                .map(|(name, value)| (name.to_lowercase(), value))
+                .collect()),
+            _ => Err(invalid_jwt(String::from(
+                "the claims namespace is missing from the token",
+            ))),
+        }
+    }
+}
+
+fn invalid_claim(name: &str) -> GQLRSError {
+    invalid_jwt(format!("the claim `{}` should be a string", name))
+}
+
+fn claim_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Result<&str, GQLRSError> {
+    let authorization = headers.get(AUTHORIZATION_HEADER).ok_or_else(|| {
+        GQLRSError::new(GQLRSErrorType::InvalidHeaders(String::from(
+            "Missing `Authorization` header in JWT authentication mode",
+        )))
+    })?;
+
+    authorization
+        .to_str()
+        .ok()
+        .and_then(|authorization| authorization.strip_prefix(BEARER_PREFIX))
+        .map(str::trim)
+        .ok_or_else(|| {
+            GQLRSError::new(GQLRSErrorType::InvalidHeaders(String::from(
+                "Malformed `Authorization` header, expected `Bearer <token>`",
+            )))
+        })
+}
+
+fn decoding_key(algorithm: JwtAlgorithm, key: &str) -> Result<DecodingKey<'static>, GQLRSError> {
+    match algorithm {
+        JwtAlgorithm::HS256 => Ok(DecodingKey::from_secret(key.as_bytes()).into_static()),
+        JwtAlgorithm::RS256 => DecodingKey::from_rsa_pem(key.as_bytes())
+            .map(DecodingKey::into_static)
+            .map_err(|err| invalid_config(&format!("the RSA key is invalid, {}", err))),
+    }
+}
+
+fn jwks_keys(
+    algorithm: JwtAlgorithm,
+    jwks_file: &str,
+) -> Result<Vec<(Option<String>, DecodingKey<'static>)>, GQLRSError> {
+    if algorithm != JwtAlgorithm::RS256 {
+        return Err(invalid_config("a JWKS file can only be used with RS256"));
+    }
+
+    let jwks = std::fs::read_to_string(jwks_file)
+        .map_err(|err| invalid_config(&format!("failed to read `{}`, {}", jwks_file, err)))?;
+    let jwks: Jwks = serde_json::from_str(&jwks)
+        .map_err(|err| invalid_config(&format!("`{}` is not a JWKS, {}", jwks_file, err)))?;
+
+    let keys: Vec<(Option<String>, DecodingKey<'static>)> = jwks
+        .keys
+        .into_iter()
+        .filter_map(|jwk| match (jwk.kty.as_str(), jwk.n, jwk.e) {
+            ("RSA", Some(n), Some(e)) => Some((
+                jwk.kid,
+                DecodingKey::from_rsa_components(&n, &e).into_static(),
+            )),
+            _ => None,
+        })
+        .collect();
+    if keys.is_empty() {
+        return Err(invalid_config(&format!("`{}` has no RSA keys", jwks_file)));
+    }
+
+    Ok(keys)
+}
+
+/// Looks up a value with a (small) subset of JSON paths, i.e. `$`
+/// followed by any of `.key`, `['key']` and `[0]`
+fn json_path_lookup<'a>(value: &'a Value, path: &str) -> Result<Option<&'a Value>, GQLRSError> {
+    let invalid_path = || invalid_config(&format!("`{}` is not a valid JSON path", path));
+
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid_path)?;
+    let mut current = Some(value);
+    while !rest.is_empty() {
+        if let Some(after_dot) = rest.strip_prefix('.') {
+            let end = after_dot.find(['.', '[']).unwrap_or(after_dot.len());
+            if end == 0 {
+                return Err(invalid_path());
+            }
+            current = current.and_then(|value| value.get(&after_dot[..end]));
+            rest = &after_dot[end..];
+        } else if let Some(after_bracket) = rest.strip_prefix('[') {
+            let end = after_bracket.find(']').ok_or_else(invalid_path)?;
+            let segment = &after_bracket[..end];
+            let quoted = segment
+                .strip_prefix('\'')
+                .and_then(|segment| segment.strip_suffix('\''))
+                .or_else(|| {
+                    segment
+                        .strip_prefix('"')
+                        .and_then(|segment| segment.strip_suffix('"'))
+                });
+            current = match quoted {
+                Some(key) => current.and_then(|value| value.get(key)),
+                None => {
+                    let index: usize = segment.parse().map_err(|_| invalid_path())?;
+                    current.and_then(|value| value.get(index))
+                }
+            };
+            rest = &after_bracket[end + 1..];
+        } else {
+            return Err(invalid_path());
+        }
+    }
+
+    Ok(current)
+}
+
+#[test]
+fn json_path_lookup_with_keys_and_indices() {
+    let claims = serde_json::json!({
+        "user": { "id": 42, "roles": ["user", "editor"] },
+        "https://example.com/claims": { "org": "acme" }
+    });
+
+    assert_eq!(json_path_lookup(&claims, "$").unwrap(), Some(&claims));
+    assert_eq!(
+        json_path_lookup(&claims, "$.user.id").unwrap(),
+        Some(&serde_json::json!(42))
+    );
+    assert_eq!(
+        json_path_lookup(&claims, "$.user.roles[1]").unwrap(),
+        Some(&serde_json::json!("editor"))
+    );
+    assert_eq!(
+        json_path_lookup(&claims, "$['https://example.com/claims'].org").unwrap(),
+        Some(&serde_json::json!("acme"))
+    );
+    assert_eq!(json_path_lookup(&claims, "$.user.name").unwrap(), None);
+    assert!(json_path_lookup(&claims, "user.id").is_err());
+    assert!(json_path_lookup(&claims, "$.user[id]").is_err());
+}
+
+#[test]
+fn jwt_config_from_str() {
+    let config: JwtConfig =
+        r#"{"type": "HS256", "key": "a-very-secret-key", "audience": ["a", "b"]}"#
+            .parse()
+            .unwrap();
+    assert_eq!(config.algorithm, JwtAlgorithm::HS256);
+    assert_eq!(
+        config.audience,
+        Some(JwtAudience::Many(vec![
+            String::from("a"),
+            String::from("b")
+        ]))
+    );
+
+    // only one of the key and the JWKS file can be used
+    let config: JwtConfig = r#"{"type": "HS256", "key": "secret", "jwks_file": "jwks.json"}"#
+        .parse()
+        .unwrap();
+    assert!(JwtAuth::new(config).is_err());
+
+    assert!(r#"{"type": "ES256", "key": "secret"}"#.parse::<JwtConfig>().is_err());
+}
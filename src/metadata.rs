@@ -1,7 +1,14 @@
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 use crate::error::{GQLRSError, GQLRSErrorType};
-use crate::utils::dquote;
+use crate::gql_types::STREAM_ROOT_FIELD_SUFFIX;
+use crate::permissions::{
+    self, DeletePermission, InheritedRole, InsertPermission, Permission, RolePermission,
+    SelectPermission, UpdatePermission,
+};
+use crate::utils::{dquote, to_camel_case, to_pascal_case};
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct QualifiedTable {
@@ -18,6 +25,17 @@ impl QualifiedTable {
             table_name: String::from(table),
         }
     }
+
+    /// The name of the root field of the table. The tables in the `public` schema go by
+    /// their own names, while the others are prefixed with their schema, so that the
+    /// tables of the same name in different schemas don't end up with the same root field
+    pub fn root_field_name(&self) -> String {
+        if self.schema_name == public_schema() {
+            self.table_name.clone()
+        } else {
+            format!("{}_{}", self.schema_name, self.table_name)
+        }
+    }
 }
 
 #[inline(always)]
@@ -36,7 +54,366 @@ impl std::fmt::Display for QualifiedTable {
     }
 }
 
-type Tables = Vec<QualifiedTable>;
+/// How the names in the database are turned into the names in the GraphQL schema
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum NamingConvention {
+    // NOTE: the names are used as they are in the database, i.e. mostly snake_case
+    #[default]
+    HasuraDefault,
+    // NOTE: types are PascalCase, fields (and arguments) are camelCase & enum values
+    // are UPPER_CASE, as is the norm in the GraphQL world
+    GraphqlDefault,
+}
+
+impl NamingConvention {
+    fn is_default(&self) -> bool {
+        self == &NamingConvention::default()
+    }
+
+    pub fn type_name(&self, name: &str) -> String {
+        match self {
+            NamingConvention::HasuraDefault => String::from(name),
+            NamingConvention::GraphqlDefault => to_pascal_case(name),
+        }
+    }
+
+    pub fn field_name(&self, name: &str) -> String {
+        match self {
+            NamingConvention::HasuraDefault => String::from(name),
+            NamingConvention::GraphqlDefault => to_camel_case(name),
+        }
+    }
+
+    pub fn enum_value(&self, value: &str) -> String {
+        match self {
+            NamingConvention::HasuraDefault => String::from(value),
+            NamingConvention::GraphqlDefault => value.to_uppercase(),
+        }
+    }
+
+    /// The suffix of the root fields that are derived from the name of a table,
+    /// like `_stream` for `<table>_stream`
+    pub fn root_field_suffix(&self, suffix: &str) -> String {
+        match self {
+            NamingConvention::HasuraDefault => String::from(suffix),
+            NamingConvention::GraphqlDefault => to_pascal_case(suffix),
+        }
+    }
+}
+
+/// The kinds of mutations, each of which has a root field of its own for every table
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MutationKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+const MUTATION_KINDS: [MutationKind; 3] = [
+    MutationKind::Insert,
+    MutationKind::Update,
+    MutationKind::Delete,
+];
+
+impl MutationKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MutationKind::Insert => "insert",
+            MutationKind::Update => "update",
+            MutationKind::Delete => "delete",
+        }
+    }
+}
+
+/// A table (or view) in the database, along with the names of its columns
+#[derive(Debug, PartialEq, Clone)]
+pub struct DbTable {
+    pub table: QualifiedTable,
+    pub columns: Vec<String>,
+}
+
+/// How a source is exposed in the GraphQL schema
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct SourceCustomization {
+    #[serde(default, skip_serializing_if = "NamingConvention::is_default")]
+    pub naming_convention: NamingConvention,
+}
+
+impl SourceCustomization {
+    fn is_empty(&self) -> bool {
+        self == &SourceCustomization::default()
+    }
+}
+
+/// The custom names of the root fields of a table. The `select_by_pk` and `select_aggregate`
+/// root fields aren't served as of now, and are kept for when they're supported
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct CustomRootFields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select_by_pk: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select_aggregate: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub insert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete: Option<String>,
+}
+
+impl CustomRootFields {
+    fn is_empty(&self) -> bool {
+        self == &CustomRootFields::default()
+    }
+
+    fn custom_names(&self) -> Vec<&String> {
+        [
+            &self.select,
+            &self.select_by_pk,
+            &self.select_aggregate,
+            &self.insert,
+            &self.update,
+            &self.delete,
+        ]
+        .iter()
+        .filter_map(|custom_name| custom_name.as_ref())
+        .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct ColumnConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub custom_name: Option<String>,
+}
+
+/// How a table is exposed in the GraphQL schema
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct TableConfiguration {
+    // NOTE: this replaces the (schema prefixed) name of the table in the GraphQL schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub custom_name: Option<String>,
+    #[serde(default, skip_serializing_if = "CustomRootFields::is_empty")]
+    pub custom_root_fields: CustomRootFields,
+    // NOTE: this is keyed by the names of the columns in the database
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub column_config: IndexMap<String, ColumnConfig>,
+}
+
+impl TableConfiguration {
+    fn is_empty(&self) -> bool {
+        self == &TableConfiguration::default()
+    }
+
+    /// The name of the column in the GraphQL schema. The custom names of the columns are
+    /// used as they are, regardless of the naming convention
+    pub fn column_graphql_name(
+        &self,
+        column_name: &str,
+        naming_convention: NamingConvention,
+    ) -> String {
+        match self
+            .column_config
+            .get(column_name)
+            .and_then(|column_config| column_config.custom_name.as_ref())
+        {
+            Some(custom_name) => custom_name.clone(),
+            None => naming_convention.field_name(column_name),
+        }
+    }
+}
+
+/// A tracked table, along with its configuration
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TableMetadata {
+    #[serde(flatten)]
+    pub table: QualifiedTable,
+    #[serde(default, skip_serializing_if = "TableConfiguration::is_empty")]
+    pub configuration: TableConfiguration,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub select_permissions: Vec<RolePermission<SelectPermission>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub insert_permissions: Vec<RolePermission<InsertPermission>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub update_permissions: Vec<RolePermission<UpdatePermission>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delete_permissions: Vec<RolePermission<DeletePermission>>,
+    // NOTE: the columns of the table in the database aren't a part of the metadata, they're
+    // filled in whenever the metadata is checked against the database
+    #[serde(skip)]
+    pub db_columns: Vec<String>,
+}
+
+impl TableMetadata {
+    pub fn new(table: QualifiedTable) -> TableMetadata {
+        TableMetadata {
+            table,
+            configuration: TableConfiguration::default(),
+            select_permissions: Vec::new(),
+            insert_permissions: Vec::new(),
+            update_permissions: Vec::new(),
+            delete_permissions: Vec::new(),
+            db_columns: Vec::new(),
+        }
+    }
+
+    /// The permission of the given kind (like a `SelectPermission`) of the role on the table
+    pub fn permission<P: Permission>(&self, role: &str) -> Option<&P> {
+        P::of_table(self)
+            .iter()
+            .find(|role_permission| role_permission.role == role)
+            .map(|role_permission| &role_permission.permission)
+    }
+
+    pub fn select_permission(&self, role: &str) -> Option<&SelectPermission> {
+        self.permission(role)
+    }
+
+    /// The name of the column in the database that the field of the table's type maps to.
+    /// The field is looked up among the GraphQL names of the columns of the table, since
+    /// the naming conventions can't always be reversed, e.g. both `a_b` and `a__b` are `aB`.
+    /// The custom names come first, and a column with a custom name can only be referred
+    /// to by its custom name
+    pub fn column_name(
+        &self,
+        graphql_name: &str,
+        naming_convention: NamingConvention,
+    ) -> Result<String, GQLRSError> {
+        let configuration = &self.configuration;
+        let custom_column = configuration
+            .column_config
+            .iter()
+            .find(|(_, column_config)| column_config.custom_name.as_deref() == Some(graphql_name))
+            .map(|(column_name, _)| column_name);
+
+        custom_column
+            .or_else(|| {
+                self.db_columns.iter().find(|column_name| {
+                    configuration.column_graphql_name(column_name, naming_convention)
+                        == graphql_name
+                })
+            })
+            .cloned()
+            .ok_or_else(|| {
+                GQLRSError::new(GQLRSErrorType::FieldNotFound(
+                    String::from(graphql_name),
+                    self.type_name(naming_convention),
+                ))
+            })
+    }
+
+    // NOTE: this is the name before the naming convention is applied to it
+    fn base_name(&self) -> String {
+        match &self.configuration.custom_name {
+            Some(custom_name) => custom_name.clone(),
+            None => self.table.root_field_name(),
+        }
+    }
+
+    /// The name of the table in the GraphQL schema
+    pub fn graphql_name(&self, naming_convention: NamingConvention) -> String {
+        naming_convention.field_name(&self.base_name())
+    }
+
+    /// The name of the type of the rows of the table
+    pub fn type_name(&self, naming_convention: NamingConvention) -> String {
+        naming_convention.type_name(&self.base_name())
+    }
+
+    // NOTE: the custom root fields are used as they are, regardless of the naming convention
+    pub fn select_root_field_name(&self, naming_convention: NamingConvention) -> String {
+        match &self.configuration.custom_root_fields.select {
+            Some(select) => select.clone(),
+            None => self.graphql_name(naming_convention),
+        }
+    }
+
+    /// The root field of the mutation, like `insert_<table>`, unless it has a custom name
+    pub fn mutation_root_field_name(
+        &self,
+        mutation_kind: MutationKind,
+        naming_convention: NamingConvention,
+    ) -> String {
+        let custom_root_fields = &self.configuration.custom_root_fields;
+        let custom_name = match mutation_kind {
+            MutationKind::Insert => &custom_root_fields.insert,
+            MutationKind::Update => &custom_root_fields.update,
+            MutationKind::Delete => &custom_root_fields.delete,
+        };
+
+        match custom_name {
+            Some(custom_name) => custom_name.clone(),
+            None => naming_convention.field_name(&format!(
+                "{}_{}",
+                mutation_kind.name(),
+                self.base_name()
+            )),
+        }
+    }
+
+    /// The name of the type of the responses of the mutation root fields of the table
+    pub fn mutation_response_type_name(&self, naming_convention: NamingConvention) -> String {
+        naming_convention.type_name(&format!("{}_mutation_response", self.base_name()))
+    }
+
+    pub fn stream_root_field_name(&self, naming_convention: NamingConvention) -> String {
+        format!(
+            "{}{}",
+            self.graphql_name(naming_convention),
+            naming_convention.root_field_suffix(STREAM_ROOT_FIELD_SUFFIX)
+        )
+    }
+
+    /// All the GraphQL names that tracking the table adds to the schema
+    fn graphql_names(&self, naming_convention: NamingConvention) -> Vec<String> {
+        let mut graphql_names = vec![
+            self.select_root_field_name(naming_convention),
+            self.stream_root_field_name(naming_convention),
+        ];
+
+        for custom_name in self.configuration.custom_root_fields.custom_names() {
+            if !graphql_names.contains(custom_name) {
+                graphql_names.push(custom_name.clone());
+            }
+        }
+
+        graphql_names
+    }
+}
+
+/// The permissions of the kind on the table that refer to columns which don't exist
+fn inconsistent_permissions<P: Permission>(
+    table: &TableMetadata,
+    db_table: &DbTable,
+) -> Vec<InconsistentObject> {
+    P::of_table(table)
+        .iter()
+        .flat_map(|role_permission| {
+            role_permission
+                .permission
+                .columns()
+                .into_iter()
+                .filter(|column| !db_table.columns.contains(column))
+                .map(move |column| InconsistentObject {
+                    object_type: P::OBJECT_TYPE,
+                    definition: serde_json::json!({
+                        "table": table.table,
+                        "role": role_permission.role,
+                        "permission": role_permission.permission,
+                    }),
+                    reason: format!(
+                        "Inconsistent object: the {} permission of the role `{}` on {} refers to the column `{}`, which doesn't exist",
+                        P::KIND, role_permission.role, table.table, column
+                    ),
+                })
+        })
+        .collect()
+}
+
+type Tables = Vec<TableMetadata>;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
 pub struct Metadata {
@@ -44,6 +421,14 @@ pub struct Metadata {
     pub source_name: String,
     #[serde(default = "default_tables")]
     pub tables: Tables,
+    // NOTE: this is bumped on every change to the metadata, so that the
+    // writes made against a stale version of the metadata can be rejected
+    #[serde(default = "initial_resource_version")]
+    pub resource_version: u64,
+    #[serde(default, skip_serializing_if = "SourceCustomization::is_empty")]
+    pub customization: SourceCustomization,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inherited_roles: Vec<InheritedRole>,
 }
 
 #[inline(always)]
@@ -51,19 +436,64 @@ fn default_tables() -> Tables {
     Vec::new()
 }
 
+#[inline(always)]
+fn initial_resource_version() -> u64 {
+    1
+}
+
 pub type MetadataResult = Result<(), GQLRSError>;
 
+#[derive(Debug, PartialEq, Serialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum InconsistentObjectType {
+    Table,
+    SelectPermission,
+    InsertPermission,
+    UpdatePermission,
+    DeletePermission,
+    InheritedRole,
+}
+
+/// An object in the metadata that doesn't agree with the database, like a tracked
+/// table that doesn't exist (anymore), or with the rest of the metadata, like an
+/// inherited role whose base role has no permissions (anymore)
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct InconsistentObject {
+    #[serde(rename = "type")]
+    pub object_type: InconsistentObjectType,
+    pub definition: serde_json::Value,
+    pub reason: String,
+}
+
 impl Metadata {
     pub fn new(source_name: &str) -> Metadata {
         Metadata {
             source_name: String::from(source_name),
             tables: Vec::new(),
+            resource_version: initial_resource_version(),
+            customization: SourceCustomization::default(),
+            inherited_roles: Vec::new(),
         }
     }
 
-    fn is_table_tracked(&self, qualified_table: &QualifiedTable) -> bool {
+    pub fn naming_convention(&self) -> NamingConvention {
+        self.customization.naming_convention
+    }
+
+    /// Replaces the customization of the source, which can change the GraphQL names of
+    /// all of its tables
+    pub fn set_source_customization(
+        &mut self,
+        customization: SourceCustomization,
+    ) -> MetadataResult {
+        self.customization = customization;
+
+        self.check_graphql_names()
+    }
+
+    pub fn is_table_tracked(&self, qualified_table: &QualifiedTable) -> bool {
         for table in &self.tables {
-            if table == qualified_table {
+            if &table.table == qualified_table {
                 return true;
             }
         }
@@ -71,6 +501,14 @@ impl Metadata {
         false
     }
 
+    /// The tables that are tracked, without their configuration
+    pub fn tracked_tables(&self) -> Vec<QualifiedTable> {
+        self.tables
+            .iter()
+            .map(|table| table.table.clone())
+            .collect()
+    }
+
     pub fn track_table(&mut self, qualified_table: QualifiedTable) -> MetadataResult {
         if self.is_table_tracked(&qualified_table) {
             return Err(GQLRSError::new(GQLRSErrorType::TableAlreadyTracked(
@@ -78,9 +516,9 @@ impl Metadata {
             )));
         }
 
-        self.tables.push(qualified_table);
+        self.tables.push(TableMetadata::new(qualified_table));
 
-        Ok(())
+        self.check_graphql_names()
     }
 
     pub fn untrack_table(&mut self, qualified_table: QualifiedTable) -> MetadataResult {
@@ -90,24 +528,349 @@ impl Metadata {
             )));
         }
 
-        self.tables.retain(|table| table != &qualified_table);
+        self.tables.retain(|table| table.table != qualified_table);
+
+        Ok(())
+    }
+
+    pub fn set_table_customization(
+        &mut self,
+        qualified_table: QualifiedTable,
+        configuration: TableConfiguration,
+    ) -> MetadataResult {
+        self.tracked_table_mut(&qualified_table)?.configuration = configuration;
+
+        self.check_graphql_names()
+    }
+
+    fn tracked_table_mut(
+        &mut self,
+        qualified_table: &QualifiedTable,
+    ) -> Result<&mut TableMetadata, GQLRSError> {
+        self.tables
+            .iter_mut()
+            .find(|table| &table.table == qualified_table)
+            .ok_or_else(|| {
+                GQLRSError::new(GQLRSErrorType::TableNotFoundInMetadata(
+                    qualified_table.to_string(),
+                ))
+            })
+    }
+
+    pub fn create_permission<P: Permission>(
+        &mut self,
+        qualified_table: QualifiedTable,
+        role: String,
+        permission: P,
+    ) -> MetadataResult {
+        permissions::check_role(&role)?;
+        permission.validate()?;
+
+        let table = self.tracked_table_mut(&qualified_table)?;
+        if table.permission::<P>(&role).is_some() {
+            return Err(GQLRSError::new(GQLRSErrorType::PermissionAlreadyExists(
+                role,
+                String::from(P::KIND),
+                qualified_table.to_string(),
+            )));
+        }
+
+        P::of_table_mut(table).push(RolePermission { role, permission });
 
         Ok(())
     }
 
-    pub fn check_for_table_in_metadata(&self, table_name: &str) -> Option<QualifiedTable> {
+    pub fn drop_permission<P: Permission>(
+        &mut self,
+        qualified_table: QualifiedTable,
+        role: String,
+    ) -> MetadataResult {
+        let table = self.tracked_table_mut(&qualified_table)?;
+        if table.permission::<P>(&role).is_none() {
+            return Err(GQLRSError::new(GQLRSErrorType::PermissionNotFound(
+                role,
+                String::from(P::KIND),
+                qualified_table.to_string(),
+            )));
+        }
+
+        P::of_table_mut(table).retain(|role_permission| role_permission.role != role);
+
+        Ok(())
+    }
+
+    pub fn inherited_role(&self, role: &str) -> Option<&InheritedRole> {
+        self.inherited_roles
+            .iter()
+            .find(|inherited_role| inherited_role.role_name == role)
+    }
+
+    /// Adds a role that inherits the permissions of the roles in its role set. The roles in
+    /// the role set have to have permissions, and can't be inherited roles themselves (nor
+    /// the inherited role, of course). The inherited role can't have permissions of its own
+    /// to begin with either
+    pub fn add_inherited_role(&mut self, inherited_role: InheritedRole) -> MetadataResult {
+        let invalid_input =
+            |message: String| GQLRSError::new(GQLRSErrorType::InvalidInput(message));
+        let role_name = &inherited_role.role_name;
+
+        permissions::check_role(role_name)?;
+        if role_name.trim().is_empty() {
+            return Err(invalid_input(String::from(
+                "The name of an inherited role can't be empty",
+            )));
+        }
+        if self.inherited_role(role_name).is_some() {
+            return Err(GQLRSError::new(GQLRSErrorType::InheritedRoleAlreadyExists(
+                role_name.clone(),
+            )));
+        }
+        if let Some(base_of) = self
+            .inherited_roles
+            .iter()
+            .find(|other| other.role_set.contains(role_name))
+        {
+            return Err(invalid_input(format!(
+                "The role `{}` is a base role of the inherited role `{}`",
+                role_name, base_of.role_name
+            )));
+        }
+        if self.has_role_permissions(role_name) {
+            return Err(invalid_input(format!(
+                "The role `{}` has permissions of its own, and so can't be an inherited role",
+                role_name
+            )));
+        }
+        if inherited_role.role_set.is_empty() {
+            return Err(invalid_input(format!(
+                "The role set of the inherited role `{}` can't be empty",
+                role_name
+            )));
+        }
+        for base_role in inherited_role.role_set.iter() {
+            permissions::check_role(base_role)?;
+            if base_role == role_name || self.inherited_role(base_role).is_some() {
+                return Err(invalid_input(format!(
+                    "The base role `{}` can't be an inherited role",
+                    base_role
+                )));
+            }
+            if !self.has_role_permissions(base_role) {
+                return Err(invalid_input(format!(
+                    "The base role `{}` doesn't have any permissions",
+                    base_role
+                )));
+            }
+        }
+
+        self.inherited_roles.push(inherited_role);
+
+        Ok(())
+    }
+
+    pub fn drop_inherited_role(&mut self, role_name: &str) -> MetadataResult {
+        if self.inherited_role(role_name).is_none() {
+            return Err(GQLRSError::new(GQLRSErrorType::InheritedRoleNotFound(
+                String::from(role_name),
+            )));
+        }
+
+        self.inherited_roles
+            .retain(|inherited_role| inherited_role.role_name != role_name);
+
+        Ok(())
+    }
+
+    /// Finds the table with the given `select` root field
+    pub fn check_for_table_in_metadata(&self, root_field_name: &str) -> Option<&TableMetadata> {
+        self.tables.iter().find(|table| {
+            table.select_root_field_name(self.naming_convention()) == *root_field_name
+        })
+    }
+
+    /// Finds the table with the given streaming root field
+    pub fn check_for_stream_table_in_metadata(
+        &self,
+        root_field_name: &str,
+    ) -> Option<&TableMetadata> {
+        self.tables.iter().find(|table| {
+            table.stream_root_field_name(self.naming_convention()) == *root_field_name
+        })
+    }
+
+    /// Finds the table with the given mutation root field, along with the kind of the mutation
+    pub fn check_for_mutation_table_in_metadata(
+        &self,
+        root_field_name: &str,
+    ) -> Option<(&TableMetadata, MutationKind)> {
+        self.tables.iter().find_map(|table| {
+            MUTATION_KINDS
+                .iter()
+                .find(|mutation_kind| {
+                    table.mutation_root_field_name(**mutation_kind, self.naming_convention())
+                        == root_field_name
+                })
+                .map(|mutation_kind| (table, *mutation_kind))
+        })
+    }
+
+    /// Makes sure that no two tracked tables end up with the same GraphQL name
+    pub fn check_graphql_names(&self) -> MetadataResult {
+        let mut graphql_names: HashMap<String, &QualifiedTable> = HashMap::new();
+
         for table in &self.tables {
-            if table.table_name == *table_name {
-                return Some(table.clone());
+            for graphql_name in table.graphql_names(self.naming_convention()) {
+                if let Some(other_table) = graphql_names.insert(graphql_name.clone(), &table.table)
+                {
+                    return Err(GQLRSError::new(GQLRSErrorType::GraphQLNameConflict(
+                        graphql_name,
+                        other_table.to_string(),
+                        table.table.to_string(),
+                    )));
+                }
             }
         }
 
-        None
+        Ok(())
+    }
+
+    /// The tables in the database that aren't tracked yet
+    pub fn untracked_tables<'a>(&self, db_tables: &'a [DbTable]) -> Vec<&'a QualifiedTable> {
+        db_tables
+            .iter()
+            .map(|db_table| &db_table.table)
+            .filter(|table| !self.is_table_tracked(table))
+            .collect()
+    }
+
+    /// Whether the role has a permission of any kind on any of the tracked tables
+    pub fn has_role_permissions(&self, role: &str) -> bool {
+        self.tables.iter().any(|table| {
+            table.permission::<SelectPermission>(role).is_some()
+                || table.permission::<InsertPermission>(role).is_some()
+                || table.permission::<UpdatePermission>(role).is_some()
+                || table.permission::<DeletePermission>(role).is_some()
+        })
     }
 
-    pub fn set_metadata(&mut self, new_md: &Metadata) {
-        // TODO?: we could perhaps do something better than this?
-        self.source_name = new_md.source_name.clone();
-        self.tables = new_md.tables.clone();
+    /// Checks the metadata against the tables (and columns) that exist in the database
+    pub fn inconsistent_objects(&self, db_tables: &[DbTable]) -> Vec<InconsistentObject> {
+        let mut inconsistent_objects = Vec::new();
+
+        for table in self.tables.iter() {
+            let db_table = match db_tables
+                .iter()
+                .find(|db_table| db_table.table == table.table)
+            {
+                Some(db_table) => db_table,
+                None => {
+                    inconsistent_objects.push(InconsistentObject {
+                        object_type: InconsistentObjectType::Table,
+                        definition: serde_json::json!(table.table),
+                        reason: format!(
+                            "Inconsistent object: no such table/view exists in source: {}",
+                            table.table
+                        ),
+                    });
+                    continue;
+                }
+            };
+
+            for column in table.configuration.column_config.keys() {
+                if !db_table.columns.contains(column) {
+                    inconsistent_objects.push(InconsistentObject {
+                        object_type: InconsistentObjectType::Table,
+                        definition: serde_json::json!(table.table),
+                        reason: format!(
+                            "Inconsistent object: the column config of {} refers to the column `{}`, which doesn't exist",
+                            table.table, column
+                        ),
+                    });
+                }
+            }
+
+            inconsistent_objects.extend(inconsistent_permissions::<SelectPermission>(
+                table, db_table,
+            ));
+            inconsistent_objects.extend(inconsistent_permissions::<InsertPermission>(
+                table, db_table,
+            ));
+            inconsistent_objects.extend(inconsistent_permissions::<UpdatePermission>(
+                table, db_table,
+            ));
+            inconsistent_objects.extend(inconsistent_permissions::<DeletePermission>(
+                table, db_table,
+            ));
+        }
+
+        for inherited_role in self.inherited_roles.iter() {
+            for base_role in inherited_role.role_set.iter() {
+                if !self.has_role_permissions(base_role) {
+                    inconsistent_objects.push(InconsistentObject {
+                        object_type: InconsistentObjectType::InheritedRole,
+                        definition: serde_json::json!(inherited_role),
+                        reason: format!(
+                            "Inconsistent object: the base role `{}` of the inherited role `{}` has no permissions",
+                            base_role, inherited_role.role_name
+                        ),
+                    });
+                }
+            }
+        }
+
+        inconsistent_objects
+    }
+
+    /// Fills in the columns of the tracked tables from the tables in the database
+    pub fn set_db_columns(&mut self, db_tables: &[DbTable]) {
+        for table in self.tables.iter_mut() {
+            table.db_columns = db_tables
+                .iter()
+                .find(|db_table| db_table.table == table.table)
+                .map(|db_table| db_table.columns.clone())
+                .unwrap_or_default();
+        }
+    }
+
+    /// Replaces the metadata with the imported one. The imported metadata is put together
+    /// one change at a time, so that it goes through the same checks as those changes would
+    pub fn set_metadata(&mut self, new_md: &Metadata) -> MetadataResult {
+        let mut metadata = Metadata::new(&new_md.source_name);
+        metadata.resource_version = self.resource_version;
+        metadata.set_source_customization(new_md.customization.clone())?;
+
+        for table in new_md.tables.iter() {
+            let qualified_table = &table.table;
+            metadata.track_table(qualified_table.clone())?;
+            metadata
+                .set_table_customization(qualified_table.clone(), table.configuration.clone())?;
+            metadata.import_permissions::<SelectPermission>(table)?;
+            metadata.import_permissions::<InsertPermission>(table)?;
+            metadata.import_permissions::<UpdatePermission>(table)?;
+            metadata.import_permissions::<DeletePermission>(table)?;
+        }
+
+        for inherited_role in new_md.inherited_roles.iter() {
+            metadata.add_inherited_role(inherited_role.clone())?;
+        }
+
+        *self = metadata;
+
+        Ok(())
+    }
+
+    fn import_permissions<P: Permission + Clone>(
+        &mut self,
+        table: &TableMetadata,
+    ) -> MetadataResult {
+        for role_permission in P::of_table(table).iter() {
+            self.create_permission(
+                table.table.clone(),
+                role_permission.role.clone(),
+                role_permission.permission.clone(),
+            )?;
+        }
+
+        Ok(())
     }
 }
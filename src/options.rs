@@ -1,5 +1,9 @@
 use clap::Clap;
 
+use crate::context::{PermissionsMode, SubscriptionsMode};
+use crate::jwt::JwtConfig;
+use crate::webhook::AuthHookMode;
+
 #[derive(Clap, Debug, Clone)]
 #[clap(
     name = "graphql-engine-rs",
@@ -19,6 +23,70 @@ pub struct Options {
     pub connection_string: String,
     #[clap(short, long, about = "server port", default_value = "3000")]
     pub port: u16,
+    #[clap(
+        long,
+        about = "interval (in milliseconds) at which live queries are re-run",
+        default_value = "1000"
+    )]
+    pub live_queries_refetch_interval: u64,
+    #[clap(
+        long,
+        about = "how live queries are refreshed, either by polling on the refetch interval or on the changes notified by the tracked tables",
+        default_value = "polling",
+        possible_values = &["polling", "listen-notify"]
+    )]
+    pub subscriptions_mode: SubscriptionsMode,
+    #[clap(
+        long,
+        about = "where the permissions are enforced, either by the engine as per the metadata or by the row level security policies of the database",
+        default_value = "engine",
+        possible_values = &["engine", "rls"]
+    )]
+    pub permissions_mode: PermissionsMode,
+    #[clap(
+        long,
+        env = "HASURA_GRAPHQL_ADMIN_SECRET",
+        about = "the secret that requests have to send in the `x-hasura-admin-secret` header to act as an admin"
+    )]
+    pub admin_secret: Option<String>,
+    #[clap(
+        long,
+        env = "HASURA_GRAPHQL_JWT_SECRET",
+        about = "the JWT mode configuration, as JSON like `{\"type\": \"HS256\", \"key\": \"...\"}`, which verifies the tokens in the `Authorization` header of the requests"
+    )]
+    pub jwt_secret: Option<JwtConfig>,
+    #[clap(
+        long,
+        env = "HASURA_GRAPHQL_AUTH_HOOK",
+        about = "the URL of a webhook that resolves the session variables of the requests from their headers"
+    )]
+    pub auth_hook: Option<String>,
+    #[clap(
+        long,
+        env = "HASURA_GRAPHQL_AUTH_HOOK_MODE",
+        about = "how the headers of the requests are sent to the auth webhook",
+        default_value = "GET",
+        possible_values = &["GET", "POST"]
+    )]
+    pub auth_hook_mode: AuthHookMode,
+    #[clap(
+        long,
+        about = "for how long (in seconds) the sessions resolved by the auth webhook are cached",
+        default_value = "5"
+    )]
+    pub auth_hook_cache_ttl: u64,
+    #[clap(
+        long,
+        about = "the headers (besides `Authorization` and `Cookie`) that the auth webhook tells the clients apart by, which its sessions are cached by",
+        use_delimiter = true
+    )]
+    pub auth_hook_headers: Vec<String>,
+    #[clap(
+        long,
+        env = "HASURA_GRAPHQL_UNAUTHORIZED_ROLE",
+        about = "the role of the requests that come without any credentials, which needs an admin secret, the JWT mode or an auth webhook"
+    )]
+    pub unauthorized_role: Option<String>,
 }
 
 pub fn parsed_options() -> Options {
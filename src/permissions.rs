@@ -0,0 +1,601 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::metadata::{InconsistentObjectType, TableMetadata};
+use crate::session::is_session_variable;
+use crate::utils::{dquote, squote};
+
+/// Builds the SQL for the value of a session variable, given its name
+pub type SessionVariableSql<'a> = &'a dyn Fn(&str) -> Result<String, GQLRSError>;
+
+// NOTE: this is only used to validate the boolean expressions in the metadata,
+// where the values of the session variables aren't known yet
+fn session_variable_placeholder(session_variable: &str) -> Result<String, GQLRSError> {
+    Ok(squote(session_variable))
+}
+
+/// The role that has access to everything, regardless of the permissions
+pub const ADMIN_ROLE: &str = "admin";
+
+/// The permission of a role on a table, as it appears in the metadata
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct RolePermission<P> {
+    pub role: String,
+    pub permission: P,
+}
+
+/// Which of the rows (and columns) of a table a role can select
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SelectPermission {
+    // NOTE: these are the names of the columns in the database
+    pub columns: Vec<String>,
+    // NOTE: the filter is a boolean expression with the same syntax as the `where`
+    // argument, i.e. `{ "author_id": { "_eq": 1 } }`, and `{}` lets every row through
+    #[serde(default = "empty_bool_exp")]
+    pub filter: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+#[inline(always)]
+fn empty_bool_exp() -> serde_json::Value {
+    serde_json::json!({})
+}
+
+impl SelectPermission {
+    pub fn allows_column(&self, column_name: &str) -> bool {
+        self.columns.iter().any(|column| column == column_name)
+    }
+}
+
+// NOTE: the values of the presets are either literals, or the names of the session
+// variables that they're taken from, like `{ "user_id": "X-Hasura-User-Id" }`
+type ColumnPresets = IndexMap<String, serde_json::Value>;
+
+/// Which of the columns a role can insert into, and which of the inserted rows are allowed
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct InsertPermission {
+    pub columns: Vec<String>,
+    // NOTE: every inserted row has to satisfy the check
+    #[serde(default = "empty_bool_exp")]
+    pub check: serde_json::Value,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub set: ColumnPresets,
+}
+
+/// Which of the rows (and columns) of a table a role can update
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct UpdatePermission {
+    pub columns: Vec<String>,
+    // NOTE: the filter picks the rows that can be updated, while
+    // the check is what the rows have to satisfy after the update
+    #[serde(default = "empty_bool_exp")]
+    pub filter: serde_json::Value,
+    #[serde(default = "empty_bool_exp")]
+    pub check: serde_json::Value,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub set: ColumnPresets,
+}
+
+/// Which of the rows of a table a role can delete
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct DeletePermission {
+    #[serde(default = "empty_bool_exp")]
+    pub filter: serde_json::Value,
+}
+
+/// The permissions of the roles on a table, one kind of permission per type
+pub trait Permission: Sized + Serialize {
+    /// The kind of the permission, like `select`
+    const KIND: &'static str;
+
+    /// The kind of the permission, as it's reported when it's inconsistent with the database
+    const OBJECT_TYPE: InconsistentObjectType;
+
+    fn of_table(table: &TableMetadata) -> &Vec<RolePermission<Self>>;
+
+    fn of_table_mut(table: &mut TableMetadata) -> &mut Vec<RolePermission<Self>>;
+
+    /// Makes sure that the boolean expressions (and presets) of the permission are valid
+    fn validate(&self) -> Result<(), GQLRSError>;
+
+    /// All the columns that the permission refers to, be it in its columns, its boolean
+    /// expressions or its presets
+    fn columns(&self) -> Vec<String>;
+}
+
+impl Permission for SelectPermission {
+    const KIND: &'static str = "select";
+    const OBJECT_TYPE: InconsistentObjectType = InconsistentObjectType::SelectPermission;
+
+    fn of_table(table: &TableMetadata) -> &Vec<RolePermission<Self>> {
+        &table.select_permissions
+    }
+
+    fn of_table_mut(table: &mut TableMetadata) -> &mut Vec<RolePermission<Self>> {
+        &mut table.select_permissions
+    }
+
+    fn validate(&self) -> Result<(), GQLRSError> {
+        bool_exp_to_sql(&self.filter, &session_variable_placeholder).map(|_| ())
+    }
+
+    fn columns(&self) -> Vec<String> {
+        referred_columns(&self.columns, &[&self.filter], None)
+    }
+}
+
+impl Permission for InsertPermission {
+    const KIND: &'static str = "insert";
+    const OBJECT_TYPE: InconsistentObjectType = InconsistentObjectType::InsertPermission;
+
+    fn of_table(table: &TableMetadata) -> &Vec<RolePermission<Self>> {
+        &table.insert_permissions
+    }
+
+    fn of_table_mut(table: &mut TableMetadata) -> &mut Vec<RolePermission<Self>> {
+        &mut table.insert_permissions
+    }
+
+    fn validate(&self) -> Result<(), GQLRSError> {
+        bool_exp_to_sql(&self.check, &session_variable_placeholder)?;
+        check_presets(&self.set)
+    }
+
+    fn columns(&self) -> Vec<String> {
+        referred_columns(&self.columns, &[&self.check], Some(&self.set))
+    }
+}
+
+impl Permission for UpdatePermission {
+    const KIND: &'static str = "update";
+    const OBJECT_TYPE: InconsistentObjectType = InconsistentObjectType::UpdatePermission;
+
+    fn of_table(table: &TableMetadata) -> &Vec<RolePermission<Self>> {
+        &table.update_permissions
+    }
+
+    fn of_table_mut(table: &mut TableMetadata) -> &mut Vec<RolePermission<Self>> {
+        &mut table.update_permissions
+    }
+
+    fn validate(&self) -> Result<(), GQLRSError> {
+        bool_exp_to_sql(&self.filter, &session_variable_placeholder)?;
+        bool_exp_to_sql(&self.check, &session_variable_placeholder)?;
+        check_presets(&self.set)
+    }
+
+    fn columns(&self) -> Vec<String> {
+        referred_columns(&self.columns, &[&self.filter, &self.check], Some(&self.set))
+    }
+}
+
+impl Permission for DeletePermission {
+    const KIND: &'static str = "delete";
+    const OBJECT_TYPE: InconsistentObjectType = InconsistentObjectType::DeletePermission;
+
+    fn of_table(table: &TableMetadata) -> &Vec<RolePermission<Self>> {
+        &table.delete_permissions
+    }
+
+    fn of_table_mut(table: &mut TableMetadata) -> &mut Vec<RolePermission<Self>> {
+        &mut table.delete_permissions
+    }
+
+    fn validate(&self) -> Result<(), GQLRSError> {
+        bool_exp_to_sql(&self.filter, &session_variable_placeholder).map(|_| ())
+    }
+
+    fn columns(&self) -> Vec<String> {
+        referred_columns(&[], &[&self.filter], None)
+    }
+}
+
+/// The columns of a permission, along with the ones in its boolean expressions and presets
+fn referred_columns(
+    columns: &[String],
+    bool_exps: &[&serde_json::Value],
+    presets: Option<&ColumnPresets>,
+) -> Vec<String> {
+    let mut referred_columns: Vec<String> = columns.to_vec();
+    for bool_exp in bool_exps {
+        bool_exp_columns(bool_exp, &mut referred_columns);
+    }
+    if let Some(presets) = presets {
+        referred_columns.extend(presets.keys().cloned());
+    }
+
+    let mut unique_columns = Vec::new();
+    for column in referred_columns {
+        if !unique_columns.contains(&column) {
+            unique_columns.push(column);
+        }
+    }
+    unique_columns
+}
+
+/// Collects the columns that a (valid) boolean expression compares
+pub fn bool_exp_columns(bool_exp: &serde_json::Value, columns: &mut Vec<String>) {
+    if let Some(fields) = bool_exp.as_object() {
+        for (key, value) in fields.iter() {
+            match key.as_str() {
+                "_and" | "_or" => {
+                    for bool_exp in value.as_array().into_iter().flatten() {
+                        bool_exp_columns(bool_exp, columns);
+                    }
+                }
+                "_not" => bool_exp_columns(value, columns),
+                column => columns.push(String::from(column)),
+            }
+        }
+    }
+}
+
+fn check_presets(presets: &ColumnPresets) -> Result<(), GQLRSError> {
+    for (column, value) in presets.iter() {
+        if value_to_sql(value, &session_variable_placeholder).is_err() {
+            return Err(GQLRSError::new(GQLRSErrorType::InvalidInput(format!(
+                "The preset of the column `{}` should be a string, a number or a boolean, found {}",
+                column, value
+            ))));
+        }
+    }
+
+    Ok(())
+}
+
+/// A role that holds the permissions of all of the roles in its role set at once
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct InheritedRole {
+    pub role_name: String,
+    pub role_set: Vec<String>,
+}
+
+/// The select permission that a query runs with. For an inherited role (without a select
+/// permission of its own), this is the union of the select permissions of its base roles
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResolvedSelectPermission {
+    pub permission: SelectPermission,
+    // NOTE: these are the columns that only some of the base roles can select, along with
+    // the rows they can be selected on, i.e. they're null on all the other rows
+    pub column_filters: IndexMap<String, serde_json::Value>,
+}
+
+impl From<SelectPermission> for ResolvedSelectPermission {
+    fn from(permission: SelectPermission) -> Self {
+        ResolvedSelectPermission {
+            permission,
+            column_filters: IndexMap::new(),
+        }
+    }
+}
+
+fn or_bool_exp(bool_exps: Vec<&serde_json::Value>) -> serde_json::Value {
+    serde_json::json!({ "_or": bool_exps })
+}
+
+/// Combines the select permissions of the base roles of an inherited role. The rows are the
+/// ones that any of the filters let through, and the columns are the ones that any of the
+/// base roles can select. There's no limit unless every base role has one
+pub fn union_select_permissions(
+    permissions: &[&SelectPermission],
+) -> Option<ResolvedSelectPermission> {
+    match permissions {
+        [] => return None,
+        [permission] => return Some((*permission).clone().into()),
+        _ => {}
+    }
+
+    let mut columns: Vec<String> = Vec::new();
+    let mut column_filters = IndexMap::new();
+    for permission in permissions.iter() {
+        for column in permission.columns.iter() {
+            if columns.contains(column) {
+                continue;
+            }
+            columns.push(column.clone());
+
+            let column_permissions: Vec<&SelectPermission> = permissions
+                .iter()
+                .filter(|permission| permission.allows_column(column))
+                .copied()
+                .collect();
+            if column_permissions.len() < permissions.len() {
+                column_filters.insert(
+                    column.clone(),
+                    or_bool_exp(
+                        column_permissions
+                            .iter()
+                            .map(|permission| &permission.filter)
+                            .collect(),
+                    ),
+                );
+            }
+        }
+    }
+
+    let limit = permissions
+        .iter()
+        .map(|permission| permission.limit)
+        .collect::<Option<Vec<u64>>>()
+        .and_then(|limits| limits.into_iter().max());
+
+    Some(ResolvedSelectPermission {
+        permission: SelectPermission {
+            columns,
+            filter: or_bool_exp(
+                permissions
+                    .iter()
+                    .map(|permission| &permission.filter)
+                    .collect(),
+            ),
+            limit,
+        },
+        column_filters,
+    })
+}
+
+/// Makes sure that permissions aren't defined for the admin role, which has access to everything
+pub fn check_role(role: &str) -> Result<(), GQLRSError> {
+    if role == ADMIN_ROLE {
+        return Err(GQLRSError::new(GQLRSErrorType::InvalidInput(format!(
+            "The permissions of the `{}` role can't be changed",
+            ADMIN_ROLE
+        ))));
+    }
+
+    Ok(())
+}
+
+fn invalid_bool_exp(message: String) -> GQLRSError {
+    GQLRSError::new(GQLRSErrorType::InvalidInput(message))
+}
+
+/// Builds the SQL condition for a boolean expression. The columns in the expression
+/// are the columns of the table that the rows are selected from, while the values
+/// can be session variables like `X-Hasura-User-Id`
+pub fn bool_exp_to_sql(
+    bool_exp: &serde_json::Value,
+    session_variable_sql: SessionVariableSql,
+) -> Result<String, GQLRSError> {
+    let fields = bool_exp.as_object().ok_or_else(|| {
+        invalid_bool_exp(format!(
+            "A boolean expression should be an object, found {}",
+            bool_exp
+        ))
+    })?;
+
+    let mut conditions: Vec<String> = Vec::new();
+
+    for (key, value) in fields.iter() {
+        let condition = match key.as_str() {
+            "_and" => bool_exps_to_sql(value, "AND", "true", session_variable_sql)?,
+            "_or" => bool_exps_to_sql(value, "OR", "false", session_variable_sql)?,
+            "_not" => format!("NOT ({})", bool_exp_to_sql(value, session_variable_sql)?),
+            column => column_comparisons_to_sql(column, value, session_variable_sql)?,
+        };
+        conditions.push(condition);
+    }
+
+    // NOTE: an empty boolean expression is always true
+    if conditions.is_empty() {
+        return Ok(String::from("true"));
+    }
+
+    Ok(conditions.join(" AND "))
+}
+
+fn bool_exps_to_sql(
+    bool_exps: &serde_json::Value,
+    operator: &str,
+    empty_value: &str,
+    session_variable_sql: SessionVariableSql,
+) -> Result<String, GQLRSError> {
+    let bool_exps = bool_exps.as_array().ok_or_else(|| {
+        invalid_bool_exp(format!(
+            "The value of `_{}` should be a list of boolean expressions",
+            operator.to_lowercase()
+        ))
+    })?;
+
+    if bool_exps.is_empty() {
+        return Ok(String::from(empty_value));
+    }
+
+    let conditions = bool_exps
+        .iter()
+        .map(|bool_exp| {
+            bool_exp_to_sql(bool_exp, session_variable_sql)
+                .map(|condition| format!("({})", condition))
+        })
+        .collect::<Result<Vec<String>, GQLRSError>>()?;
+
+    Ok(format!("({})", conditions.join(&format!(" {} ", operator))))
+}
+
+fn column_comparisons_to_sql(
+    column: &str,
+    comparisons: &serde_json::Value,
+    session_variable_sql: SessionVariableSql,
+) -> Result<String, GQLRSError> {
+    let comparisons = comparisons.as_object().ok_or_else(|| {
+        invalid_bool_exp(format!(
+            "The comparisons on the column `{}` should be an object",
+            column
+        ))
+    })?;
+
+    let column_sql = dquote(column);
+    let mut conditions: Vec<String> = Vec::new();
+
+    for (operator, value) in comparisons.iter() {
+        let condition = match operator.as_str() {
+            "_in" => list_to_sql(value, session_variable_sql)?
+                .map(|values| format!("{} IN ({})", column_sql, values))
+                .unwrap_or_else(|| String::from("false")),
+            "_nin" => list_to_sql(value, session_variable_sql)?
+                .map(|values| format!("{} NOT IN ({})", column_sql, values))
+                .unwrap_or_else(|| String::from("true")),
+            "_is_null" => match value.as_bool() {
+                Some(true) => format!("{} IS NULL", column_sql),
+                Some(false) => format!("{} IS NOT NULL", column_sql),
+                None => {
+                    return Err(invalid_bool_exp(String::from(
+                        "The value of `_is_null` should be a boolean",
+                    )))
+                }
+            },
+            _ => match comparison_operator_sql(operator) {
+                Some(operator_sql) => format!(
+                    "{} {} {}",
+                    column_sql,
+                    operator_sql,
+                    value_to_sql(value, session_variable_sql)?
+                ),
+                None => {
+                    return Err(invalid_bool_exp(format!(
+                        "Unknown operator `{}` on the column `{}`",
+                        operator, column
+                    )))
+                }
+            },
+        };
+        conditions.push(condition);
+    }
+
+    if conditions.is_empty() {
+        return Ok(String::from("true"));
+    }
+
+    Ok(conditions.join(" AND "))
+}
+
+fn comparison_operator_sql(operator: &str) -> Option<&'static str> {
+    match operator {
+        "_eq" => Some("="),
+        "_neq" => Some("<>"),
+        "_gt" => Some(">"),
+        "_lt" => Some("<"),
+        "_gte" => Some(">="),
+        "_lte" => Some("<="),
+        "_like" => Some("LIKE"),
+        "_nlike" => Some("NOT LIKE"),
+        "_ilike" => Some("ILIKE"),
+        "_nilike" => Some("NOT ILIKE"),
+        _ => None,
+    }
+}
+
+// NOTE: the values are left as untyped literals, so that Postgres reads
+// them as values of the type of the column that they're compared with (or set to)
+pub fn value_to_sql(
+    value: &serde_json::Value,
+    session_variable_sql: SessionVariableSql,
+) -> Result<String, GQLRSError> {
+    match value {
+        serde_json::Value::String(text) if is_session_variable(text) => session_variable_sql(text),
+        serde_json::Value::String(text) => Ok(squote(text)),
+        serde_json::Value::Number(num) => Ok(squote(&num.to_string())),
+        serde_json::Value::Bool(flag) => Ok(squote(&flag.to_string())),
+        _ => Err(invalid_bool_exp(format!(
+            "Only strings, numbers and booleans can be compared with, found {}",
+            value
+        ))),
+    }
+}
+
+/// The values of a list as a SQL list, which is `None` for an empty list
+fn list_to_sql(
+    values: &serde_json::Value,
+    session_variable_sql: SessionVariableSql,
+) -> Result<Option<String>, GQLRSError> {
+    let values = values
+        .as_array()
+        .ok_or_else(|| invalid_bool_exp(format!("Expected a list of values, found {}", values)))?;
+
+    if values.is_empty() {
+        return Ok(None);
+    }
+
+    let values_sql = values
+        .iter()
+        .map(|value| value_to_sql(value, session_variable_sql))
+        .collect::<Result<Vec<String>, GQLRSError>>()?;
+
+    Ok(Some(values_sql.join(", ")))
+}
+
+#[test]
+fn bool_exp_to_sql_with_nested_operators() {
+    let bool_exp = serde_json::json!({
+        "_or": [
+            { "id": { "_eq": 1 } },
+            { "_not": { "name": { "_is_null": true } }, "age": { "_in": [23, 24] } }
+        ]
+    });
+    assert_eq!(
+        bool_exp_to_sql(&bool_exp, &session_variable_placeholder).unwrap(),
+        "((\"id\" = '1') OR (NOT (\"name\" IS NULL) AND \"age\" IN ('23', '24')))".to_string()
+    );
+    assert_eq!(
+        bool_exp_to_sql(&serde_json::json!({}), &session_variable_placeholder).unwrap(),
+        "true".to_string()
+    );
+}
+
+#[test]
+fn bool_exp_to_sql_with_invalid_operator() {
+    let bool_exp = serde_json::json!({ "id": { "_matches": 1 } });
+    assert!(bool_exp_to_sql(&bool_exp, &session_variable_placeholder).is_err());
+}
+
+#[test]
+fn union_select_permissions_of_base_roles() {
+    let author = SelectPermission {
+        columns: vec![
+            String::from("id"),
+            String::from("name"),
+            String::from("email"),
+        ],
+        filter: serde_json::json!({ "id": { "_eq": "X-Hasura-User-Id" } }),
+        limit: Some(10),
+    };
+    let reader = SelectPermission {
+        columns: vec![String::from("id"), String::from("name")],
+        filter: serde_json::json!({}),
+        limit: None,
+    };
+
+    assert_eq!(union_select_permissions(&[]), None);
+    assert_eq!(
+        union_select_permissions(&[&reader]),
+        Some(ResolvedSelectPermission::from(reader.clone()))
+    );
+
+    let resolved = union_select_permissions(&[&author, &reader]).unwrap();
+    assert_eq!(
+        resolved.permission,
+        SelectPermission {
+            columns: vec![
+                String::from("id"),
+                String::from("name"),
+                String::from("email")
+            ],
+            filter: serde_json::json!({
+                "_or": [{ "id": { "_eq": "X-Hasura-User-Id" } }, {}]
+            }),
+            limit: None,
+        }
+    );
+    // only the author can see the email, and only on their own row
+    assert_eq!(
+        resolved.column_filters,
+        vec![(
+            String::from("email"),
+            serde_json::json!({ "_or": [{ "id": { "_eq": "X-Hasura-User-Id" } }] })
+        )]
+        .into_iter()
+        .collect::<IndexMap<String, serde_json::Value>>()
+    );
+}
@@ -0,0 +1,97 @@
+use actix_web::http::HeaderMap;
+use indexmap::IndexMap;
+
+use crate::auth::ADMIN_SECRET_HEADER;
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::permissions::ADMIN_ROLE;
+
+/// The prefix of the request headers (and of the names) of the session variables
+pub const SESSION_VARIABLE_PREFIX: &str = "x-hasura-";
+
+pub const ROLE_SESSION_VARIABLE: &str = "x-hasura-role";
+
+/// Whether the value (like `X-Hasura-User-Id`) refers to a session variable.
+/// Session variables are case-insensitive, just like the headers they come from
+pub fn is_session_variable(value: &str) -> bool {
+    value.to_lowercase().starts_with(SESSION_VARIABLE_PREFIX)
+}
+
+/// The session variables of a request, keyed by their lowercased names.
+/// There's always an `x-hasura-role`, which is the admin role unless the request says otherwise
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionVariables(IndexMap<String, String>);
+
+impl Default for SessionVariables {
+    fn default() -> Self {
+        let mut session_variables = IndexMap::new();
+        session_variables.insert(
+            String::from(ROLE_SESSION_VARIABLE),
+            String::from(ADMIN_ROLE),
+        );
+        SessionVariables(session_variables)
+    }
+}
+
+impl SessionVariables {
+    /// Collects the `x-hasura-*` headers of a request into its session
+    pub fn from_headers(headers: &HeaderMap) -> Result<SessionVariables, GQLRSError> {
+        let mut session_variables = SessionVariables::default();
+
+        for (name, value) in headers.iter() {
+            // NOTE: the names of the headers are always lowercase
+            let name = name.as_str();
+            // NOTE: the admin secret proves who made the request, and is kept out of the
+            // session so that it never makes it to the database
+            if !is_session_variable(name) || name == ADMIN_SECRET_HEADER {
+                continue;
+            }
+
+            let value = value.to_str().map_err(|_| {
+                GQLRSError::new(GQLRSErrorType::InvalidHeaders(format!(
+                    "The value of the session variable `{}` should be a visible ASCII string",
+                    name
+                )))
+            })?;
+            session_variables.insert(name, value)?;
+        }
+
+        Ok(session_variables)
+    }
+
+    /// The session of a request that has nothing but a role to go by
+    pub fn with_role(role: &str) -> Result<SessionVariables, GQLRSError> {
+        let mut session_variables = SessionVariables::default();
+        session_variables.insert(ROLE_SESSION_VARIABLE, role)?;
+        Ok(session_variables)
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) -> Result<(), GQLRSError> {
+        let name = name.to_lowercase();
+        if name == ROLE_SESSION_VARIABLE && value.trim().is_empty() {
+            return Err(GQLRSError::new(GQLRSErrorType::InvalidHeaders(format!(
+                "The session variable `{}` can't be empty",
+                ROLE_SESSION_VARIABLE
+            ))));
+        }
+
+        self.0.insert(name, String::from(value));
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(&name.to_lowercase()).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter()
+    }
+
+    pub fn role(&self) -> &str {
+        self.get(ROLE_SESSION_VARIABLE).unwrap_or(ADMIN_ROLE)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!(self.0)
+    }
+}
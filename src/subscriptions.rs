@@ -0,0 +1,542 @@
+use futures::channel::mpsc::UnboundedSender;
+use log::{debug, warn};
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::types::Json;
+use postgres::{Client, Row};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::context::{AppState, PermissionsMode, SubscriptionsMode};
+use crate::db;
+use crate::error::GQLRSError;
+use crate::gql_types::{RootFields, SESSION_VARIABLES_VARIABLE, STREAM_CURSOR_VARIABLE};
+use crate::graphql::{GQLResult, OperationFields};
+use crate::metadata::{Metadata, QualifiedTable};
+use crate::session::SessionVariables;
+
+pub type SubscriberId = u64;
+
+pub type LiveQueryResult = Result<GQLResult, String>;
+
+/// This is what gets pushed to a subscriber every time the result of its live query changes
+#[derive(Debug, Clone)]
+pub struct LiveQueryEvent {
+    pub operation_id: String,
+    pub result: LiveQueryResult,
+}
+
+/// The result of a live query, in the shape of a GraphQL execution result
+pub fn to_execution_result(result: LiveQueryResult) -> serde_json::Value {
+    match result {
+        Ok(data) => serde_json::json!({ "data": data }),
+        Err(err) => serde_json::json!({ "errors": [{ "message": err }] }),
+    }
+}
+
+struct Subscriber {
+    // NOTE: this is the id given to the operation by the client
+    operation_id: String,
+    // NOTE: the variables are kept as a JSON object since that's
+    // what the multiplexed query reads them from
+    variables: serde_json::Value,
+    sender: UnboundedSender<LiveQueryEvent>,
+    last_result_hash: Option<u64>,
+}
+
+impl Subscriber {
+    /// Pushes the result to the subscriber, only if it's different from the last one
+    fn push(&mut self, result: LiveQueryResult) {
+        let result_hash = live_query_result_hash(&result);
+
+        if self.last_result_hash == Some(result_hash) {
+            return;
+        }
+
+        self.last_result_hash = Some(result_hash);
+        self.send(result);
+    }
+
+    fn send(&self, result: LiveQueryResult) {
+        let _ = self.sender.unbounded_send(LiveQueryEvent {
+            operation_id: self.operation_id.clone(),
+            result,
+        });
+    }
+
+    /// Pushes the next batch of a streaming subscription to the subscriber and moves
+    /// its cursor past the batch. Nothing is pushed when there are no new rows.
+    fn push_batch(&mut self, result: LiveQueryResult, cursor: Option<serde_json::Value>) {
+        match (result, cursor) {
+            (Ok(data), Some(cursor)) => {
+                if let Some(variables) = self.variables.as_object_mut() {
+                    variables.insert(String::from(STREAM_CURSOR_VARIABLE), cursor);
+                }
+                self.send(Ok(data));
+            }
+            (Ok(_), None) => {}
+            (Err(err), _) => self.push(Err(err)),
+        }
+    }
+}
+
+/// The subscribers of a cohort have all subscribed to the same query (but possibly
+/// with different variables), so they're polled together with a single SQL query
+struct Cohort {
+    root_fields: RootFields,
+    // NOTE: the session that the live query runs as, of which the whole cohort shares
+    // the role (and in the RLS mode, all of the session variables)
+    session_variables: SessionVariables,
+    subscribers: HashMap<SubscriberId, Subscriber>,
+    // NOTE: this is only used when the subscriptions are refreshed on the changes
+    // notified by the tracked tables, instead of being polled on an interval
+    needs_refresh: bool,
+}
+
+impl Cohort {
+    fn is_stream(&self) -> bool {
+        self.root_fields
+            .values()
+            .any(|field_info| field_info.stream_cursor().is_some())
+    }
+
+    /// Whether the live query of the cohort fetches its rows from any of the given tables
+    fn touches_tables(&self, metadata: &Metadata, tables: &[QualifiedTable]) -> bool {
+        self.root_fields.iter().any(|(root_field, field_info)| {
+            db::root_field_table(root_field, field_info, metadata)
+                .is_some_and(|table| tables.contains(&table.table))
+        })
+    }
+
+    /// A copy of what it takes to run the live query of the cohort, so that the query
+    /// can run without holding on to the live queries
+    fn query(&self, key: &CohortKey) -> CohortQuery {
+        let (subscriber_ids, subscriber_variables): (Vec<i64>, Vec<serde_json::Value>) = self
+            .subscribers
+            .iter()
+            .map(|(id, subscriber)| (*id as i64, subscriber.variables.clone()))
+            .unzip();
+
+        CohortQuery {
+            key: key.clone(),
+            root_fields: self.root_fields.clone(),
+            session_variables: self.session_variables.clone(),
+            subscriber_ids,
+            subscriber_variables,
+        }
+    }
+
+    /// Pushes the results of the live query of the cohort to the subscribers that it ran for.
+    /// Returns whether the cohort needs to be polled again right away, which is when a
+    /// streaming subscriber was pushed a new batch (since there could be more rows past the
+    /// cursor waiting to be streamed) or when subscribers joined while the query was running.
+    fn push_results(&mut self, query: &CohortQuery, rows: Result<Vec<Row>, GQLRSError>) -> bool {
+        let has_new_subscribers = self
+            .subscribers
+            .keys()
+            .any(|id| !query.subscriber_ids.contains(&(*id as i64)));
+
+        let rows = match rows {
+            Ok(rows) => rows,
+            // NOTE: when the query fails, it fails for every subscriber of the cohort
+            Err(err) => {
+                for subscriber_id in query.subscriber_ids.iter() {
+                    if let Some(subscriber) =
+                        self.subscribers.get_mut(&(*subscriber_id as SubscriberId))
+                    {
+                        subscriber.push(Err(err.to_string()));
+                    }
+                }
+                return has_new_subscribers;
+            }
+        };
+
+        let is_stream = self.is_stream();
+        let mut has_new_batches = false;
+
+        for row in rows.iter() {
+            let subscriber_id: i64 = match row.try_get("subscriber_id") {
+                Ok(id) => id,
+                Err(err) => {
+                    warn!("Failed to read the subscriber id of a live query: {}", err);
+                    continue;
+                }
+            };
+            let result: Result<Json<GQLResult>, postgres::Error> = row.try_get("data");
+            let result = result.map(|data| data.0).map_err(|err| err.to_string());
+
+            if let Some(subscriber) = self.subscribers.get_mut(&(subscriber_id as SubscriberId)) {
+                if is_stream {
+                    let cursor: Option<Json<serde_json::Value>> =
+                        row.try_get("cursor").unwrap_or(None);
+                    has_new_batches |= result.is_ok() && cursor.is_some();
+                    subscriber.push_batch(result, cursor.map(|cursor| cursor.0));
+                } else {
+                    subscriber.push(result);
+                }
+            }
+        }
+
+        has_new_batches || has_new_subscribers
+    }
+}
+
+/// The live query of a cohort, as it was when the cohort was picked to be polled
+struct CohortQuery {
+    key: CohortKey,
+    root_fields: RootFields,
+    session_variables: SessionVariables,
+    subscriber_ids: Vec<i64>,
+    subscriber_variables: Vec<serde_json::Value>,
+}
+
+impl CohortQuery {
+    fn run(
+        &self,
+        pg_client: &mut Client,
+        metadata: &Metadata,
+        permissions_mode: PermissionsMode,
+    ) -> Result<Vec<Row>, GQLRSError> {
+        db::get_multiplexed_rows_gql_query(
+            pg_client,
+            &self.root_fields,
+            metadata,
+            &self.subscriber_ids,
+            &self.subscriber_variables,
+            &self.session_variables,
+            permissions_mode,
+        )
+    }
+}
+
+// NOTE: the queries of two subscribers are the same when they resolve to the same
+// root fields (and run as the same role, or in the RLS mode, with the same session
+// variables, since the database reads those off of the transaction), and since
+// `FieldName`s can't be keys of a JSON object, we use the `Debug` representation
+// of the root fields in the key instead
+type CohortKey = String;
+
+fn cohort_key(
+    root_fields: &RootFields,
+    session_variables: &SessionVariables,
+    permissions_mode: PermissionsMode,
+) -> CohortKey {
+    match permissions_mode {
+        PermissionsMode::Engine => format!("{}:{:?}", session_variables.role(), root_fields),
+        PermissionsMode::Rls => format!("{}:{:?}", session_variables.to_json(), root_fields),
+    }
+}
+
+#[derive(Default)]
+struct LiveQueries {
+    cohorts: HashMap<CohortKey, Cohort>,
+    subscriber_cohorts: HashMap<SubscriberId, CohortKey>,
+}
+
+impl LiveQueries {
+    fn remove_subscriber(&mut self, subscriber_id: SubscriberId) {
+        if let Some(key) = self.subscriber_cohorts.remove(&subscriber_id) {
+            if let Some(cohort) = self.cohorts.get_mut(&key) {
+                cohort.subscribers.remove(&subscriber_id);
+                if cohort.subscribers.is_empty() {
+                    self.cohorts.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Drops the subscribers whose transport has gone away
+    fn remove_closed_subscribers(&mut self) {
+        let closed_subscriber_ids: Vec<SubscriberId> = self
+            .cohorts
+            .values()
+            .flat_map(|cohort| cohort.subscribers.iter())
+            .filter(|(_, subscriber)| subscriber.sender.is_closed())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for subscriber_id in closed_subscriber_ids {
+            self.remove_subscriber(subscriber_id);
+        }
+    }
+}
+
+/// The `LiveQueryPoller` re-runs the live queries of all the subscribers on an
+/// interval and pushes the result to a subscriber only when it has changed.
+/// Streaming subscriptions are instead pushed every new batch of rows past their cursor.
+/// Subscribers are grouped into cohorts, with one SQL query per cohort per poll.
+///
+/// In the `ListenNotify` mode, the live queries are re-run only when the tables that
+/// they touch are changed (and when new subscribers join), rather than on every poll.
+#[derive(Clone)]
+pub struct LiveQueryPoller {
+    live_queries: Arc<Mutex<LiveQueries>>,
+    next_subscriber_id: Arc<AtomicU64>,
+    permissions_mode: PermissionsMode,
+}
+
+impl LiveQueryPoller {
+    /// Starts polling the live queries on a separate thread
+    pub fn start(
+        app_state: actix_web::web::Data<AppState>,
+        refetch_interval: Duration,
+    ) -> actix_web::web::Data<LiveQueryPoller> {
+        let (subscriptions_mode, permissions_mode) = {
+            let server_ctx = app_state.0.lock().unwrap();
+            (
+                server_ctx.get_subscriptions_mode(),
+                server_ctx.get_permissions_mode(),
+            )
+        };
+        let poller = LiveQueryPoller {
+            live_queries: Arc::new(Mutex::new(LiveQueries::default())),
+            next_subscriber_id: Arc::new(AtomicU64::new(0)),
+            permissions_mode,
+        };
+
+        let thread_poller = poller.clone();
+        match subscriptions_mode {
+            SubscriptionsMode::Polling => {
+                std::thread::spawn(move || loop {
+                    std::thread::sleep(refetch_interval);
+                    thread_poller.poll(&app_state, None);
+                });
+            }
+            SubscriptionsMode::ListenNotify => {
+                let pg_pool = app_state.0.lock().unwrap().get_connection_pool().clone();
+                db::listen_on(
+                    pg_pool,
+                    db::TABLE_CHANGE_CHANNEL,
+                    refetch_interval,
+                    move |listener| thread_poller.listen(listener, &app_state, refetch_interval),
+                );
+            }
+        };
+
+        actix_web::web::Data::new(poller)
+    }
+
+    pub fn add_subscriber(
+        &self,
+        operation_id: String,
+        operation_fields: OperationFields,
+        session_variables: SessionVariables,
+        sender: UnboundedSender<LiveQueryEvent>,
+    ) -> SubscriberId {
+        let subscriber_id = self.next_subscriber_id.fetch_add(1, Ordering::SeqCst);
+        let key = cohort_key(
+            &operation_fields.root_fields,
+            &session_variables,
+            self.permissions_mode,
+        );
+        // NOTE: in the engine mode, the subscribers of a cohort share the role, but the
+        // rest of their session variables are read off of their own variables
+        let mut variables = operation_fields.variables.clone();
+        variables.insert(
+            String::from(SESSION_VARIABLES_VARIABLE),
+            session_variables.to_json(),
+        );
+        let subscriber = Subscriber {
+            operation_id,
+            variables: serde_json::json!(variables),
+            sender,
+            last_result_hash: None,
+        };
+
+        let mut live_queries = self.live_queries.lock().unwrap();
+        let cohort = live_queries
+            .cohorts
+            .entry(key.clone())
+            .or_insert_with(|| Cohort {
+                root_fields: operation_fields.root_fields,
+                session_variables,
+                subscribers: HashMap::new(),
+                needs_refresh: true,
+            });
+        // NOTE: the new subscriber has to be sent its first result, regardless
+        // of whether the tables of the cohort have changed
+        cohort.needs_refresh = true;
+        cohort.subscribers.insert(subscriber_id, subscriber);
+        live_queries.subscriber_cohorts.insert(subscriber_id, key);
+
+        subscriber_id
+    }
+
+    pub fn remove_subscriber(&self, subscriber_id: SubscriberId) {
+        self.live_queries
+            .lock()
+            .unwrap()
+            .remove_subscriber(subscriber_id);
+    }
+
+    /// Listens to the changes notified by the tracked tables, and re-runs the live queries
+    /// that touch the changed tables. Returns only when the connection stops working.
+    fn listen(&self, listener: &mut Client, app_state: &AppState, refetch_interval: Duration) {
+        if let Err(err) = db::create_table_change_notify_function(listener) {
+            warn!(
+                "Failed to listen for changes to the tracked tables: {}",
+                err
+            );
+            return;
+        }
+
+        let mut triggered_tables: Vec<QualifiedTable> = Vec::new();
+        let mut needs_refresh = false;
+
+        loop {
+            // NOTE: the triggers are installed on the tables as they get tracked, but they're
+            // never removed while listening, since the other instances may be listening for
+            // the changes too. It's the untracking of a table that removes its trigger
+            let tracked_tables = app_state.0.lock().unwrap().get_metadata().tracked_tables();
+            for table in tracked_tables.iter() {
+                if !triggered_tables.contains(table) {
+                    if let Err(err) = db::install_table_change_trigger(listener, table) {
+                        warn!("Failed to install the change trigger on {}: {}", table, err);
+                        continue;
+                    }
+                    triggered_tables.push(table.clone());
+                }
+            }
+            triggered_tables.retain(|table| tracked_tables.contains(table));
+
+            let mut notifications = listener.notifications();
+            // NOTE: we wait for the first notification for up to the refetch interval,
+            // and then take all the notifications that have arrived along with it. The
+            // cohorts that need a refresh right away don't wait on any notification though
+            let wait_interval = if needs_refresh {
+                Duration::from_secs(0)
+            } else {
+                refetch_interval
+            };
+            let first_notification = match notifications.timeout_iter(wait_interval).next() {
+                Ok(notification) => notification,
+                Err(err) => {
+                    warn!(
+                        "Failed to receive the changes to the tracked tables: {}",
+                        err
+                    );
+                    return;
+                }
+            };
+            let mut pending_notifications = Vec::new();
+            if let Some(notification) = first_notification {
+                pending_notifications.push(notification);
+                let mut rest = notifications.iter();
+                while let Ok(Some(notification)) = rest.next() {
+                    pending_notifications.push(notification);
+                }
+            }
+
+            let mut changed_tables: Vec<QualifiedTable> = Vec::new();
+            for notification in pending_notifications {
+                match serde_json::from_str::<QualifiedTable>(notification.payload()) {
+                    Ok(table) => {
+                        if !changed_tables.contains(&table) {
+                            changed_tables.push(table);
+                        }
+                    }
+                    Err(err) => warn!("Failed to parse the change notification: {}", err),
+                }
+            }
+
+            needs_refresh = self.poll(app_state, Some(&changed_tables));
+        }
+    }
+
+    /// Re-runs the live queries of the cohorts. When the changed tables are given,
+    /// only the cohorts that touch them (or that need a refresh otherwise) are re-run.
+    /// Returns whether any of the cohorts needs to be re-run again right away
+    fn poll(&self, app_state: &AppState, changed_tables: Option<&[QualifiedTable]>) -> bool {
+        // NOTE: we hold on to the server context only for as long as it takes
+        // to get a connection and a copy of the metadata
+        let (pg_pool, metadata) = {
+            let server_ctx = app_state.0.lock().unwrap();
+            (
+                server_ctx.get_connection_pool().clone(),
+                server_ctx.get_metadata().clone(),
+            )
+        };
+
+        // NOTE: the live queries are only locked while the cohorts to poll are picked and
+        // while the results are pushed, so that subscribers can come and go while the
+        // queries are running
+        let cohort_queries: Vec<CohortQuery> = {
+            let mut live_queries = self.live_queries.lock().unwrap();
+
+            live_queries.remove_closed_subscribers();
+
+            for cohort in live_queries.cohorts.values_mut() {
+                cohort.needs_refresh = match changed_tables {
+                    None => true,
+                    Some(tables) => {
+                        cohort.needs_refresh || cohort.touches_tables(&metadata, tables)
+                    }
+                };
+            }
+
+            live_queries
+                .cohorts
+                .iter()
+                .filter(|(_, cohort)| cohort.needs_refresh)
+                .map(|(key, cohort)| cohort.query(key))
+                .collect()
+        };
+
+        if cohort_queries.is_empty() {
+            return false;
+        }
+
+        debug!(
+            "Polling live queries for {} subscriber(s) in {} cohort(s)",
+            cohort_queries
+                .iter()
+                .map(|query| query.subscriber_ids.len())
+                .sum::<usize>(),
+            cohort_queries.len()
+        );
+
+        let mut pg_client = match pg_pool.get() {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("Failed to get a connection to poll live queries: {}", err);
+                return false;
+            }
+        };
+
+        let results: Vec<(CohortQuery, Result<Vec<Row>, GQLRSError>)> = cohort_queries
+            .into_iter()
+            .map(|query| {
+                let rows = query.run(&mut pg_client, &metadata, self.permissions_mode);
+                (query, rows)
+            })
+            .collect();
+
+        let mut live_queries = self.live_queries.lock().unwrap();
+        let mut needs_refresh = false;
+        for (query, rows) in results {
+            // NOTE: the cohort is gone when all of its subscribers left while polling
+            if let Some(cohort) = live_queries.cohorts.get_mut(&query.key) {
+                cohort.needs_refresh = cohort.push_results(&query, rows);
+                needs_refresh |= cohort.needs_refresh;
+            }
+        }
+        needs_refresh
+    }
+}
+
+fn live_query_result_hash(result: &LiveQueryResult) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    result.is_ok().hash(&mut hasher);
+    match result {
+        Ok(data) => serde_json::to_string(data)
+            .unwrap_or_default()
+            .hash(&mut hasher),
+        Err(err) => err.hash(&mut hasher),
+    }
+
+    hasher.finish()
+}
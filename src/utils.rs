@@ -1,9 +1,15 @@
 use convert_case::{Case, Casing};
 
 pub fn dquote(s: &str) -> String {
-    format!("\"{}\"", s)
+    format!("\"{}\"", s.replace('"', "\"\""))
 }
 
+/// Quotes a string literal for SQL, escaping any single quotes within it
+pub fn squote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[allow(dead_code)]
 pub fn to_snake_case(s: &str) -> String {
     s.to_case(Case::Snake)
 }
@@ -12,7 +18,12 @@ pub fn to_camel_case(s: &str) -> String {
     s.to_case(Case::Camel)
 }
 
+pub fn to_pascal_case(s: &str) -> String {
+    s.to_case(Case::Pascal)
+}
+
 /// CAUTION: this method is unsafe!
+#[allow(dead_code)]
 pub fn string_to_static_str(s: String) -> &'static str {
     Box::leak(s.into_boxed_str())
 }
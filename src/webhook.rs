@@ -0,0 +1,337 @@
+use actix_web::http::{HeaderMap, StatusCode};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{GQLRSError, GQLRSErrorType};
+use crate::session::{is_session_variable, SessionVariables, ROLE_SESSION_VARIABLE};
+
+/// The headers that aren't about who the client is, and so aren't forwarded to the webhook
+const IGNORED_HEADERS: [&str; 16] = [
+    "accept",
+    "accept-datetime",
+    "accept-encoding",
+    "accept-language",
+    "cache-control",
+    "connection",
+    "content-length",
+    "content-md5",
+    "content-type",
+    "dnt",
+    "host",
+    "origin",
+    "referer",
+    "transfer-encoding",
+    "upgrade",
+    "user-agent",
+];
+
+/// The headers that carry the credentials of a client, whatever else the webhook looks at
+const CREDENTIAL_HEADERS: [&str; 2] = ["authorization", "cookie"];
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// NOTE: once the cache is full, the oldest session is evicted to make room for a new one
+const MAX_CACHED_SESSIONS: usize = 10_000;
+
+/// How the headers of a request are forwarded to the webhook
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AuthHookMode {
+    /// As the headers of a `GET` request
+    Get,
+    /// As the `headers` of the JSON body of a `POST` request
+    Post,
+}
+
+impl std::str::FromStr for AuthHookMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(AuthHookMode::Get),
+            "POST" => Ok(AuthHookMode::Post),
+            _ => Err(format!(
+                "unknown auth hook mode `{}`, expected one of GET, POST",
+                s
+            )),
+        }
+    }
+}
+
+type CacheKey = Vec<(String, String)>;
+
+#[derive(Debug)]
+struct CachedSession {
+    session_variables: SessionVariables,
+    cached_at: Instant,
+}
+
+/// Resolves the sessions of the requests by asking a webhook about their headers
+#[derive(Debug, Clone)]
+pub struct AuthWebhook {
+    url: String,
+    mode: AuthHookMode,
+    cache_ttl: Duration,
+    // NOTE: these are the names (in lowercase) of the headers, other than the credential
+    // headers, that the webhook tells the clients apart by
+    auth_headers: Vec<String>,
+    // NOTE: the cache is shared by all the clones of the webhook, i.e. by every worker
+    cache: Arc<Mutex<HashMap<CacheKey, CachedSession>>>,
+}
+
+fn auth_hook_error(reason: String) -> GQLRSError {
+    GQLRSError::new(GQLRSErrorType::AuthHookError(reason))
+}
+
+impl AuthWebhook {
+    pub fn new(url: &str, mode: AuthHookMode, cache_ttl: Duration) -> AuthWebhook {
+        AuthWebhook {
+            url: String::from(url),
+            mode,
+            cache_ttl,
+            auth_headers: Vec::new(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_auth_headers(mut self, auth_headers: &[String]) -> AuthWebhook {
+        self.auth_headers = auth_headers
+            .iter()
+            .map(|auth_header| auth_header.to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Resolves the session of a request with the webhook, unless the same client
+    /// has been seen within the time-to-live of the cache
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<SessionVariables, GQLRSError> {
+        let cache_key = cache_key(headers, &self.auth_headers);
+        if let Some(session_variables) = self.cached_session(&cache_key) {
+            return Ok(session_variables);
+        }
+
+        let session_variables = self.call_webhook(headers).await?;
+
+        if !self.cache_ttl.is_zero() {
+            let mut cache = self.cache.lock().unwrap();
+            let cache_ttl = self.cache_ttl;
+            cache.retain(|_, cached| cached.cached_at.elapsed() < cache_ttl);
+            if cache.len() >= MAX_CACHED_SESSIONS {
+                let oldest_key = cache
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.cached_at)
+                    .map(|(cache_key, _)| cache_key.clone());
+                if let Some(oldest_key) = oldest_key {
+                    cache.remove(&oldest_key);
+                }
+            }
+            cache.insert(
+                cache_key,
+                CachedSession {
+                    session_variables: session_variables.clone(),
+                    cached_at: Instant::now(),
+                },
+            );
+        }
+
+        Ok(session_variables)
+    }
+
+    fn cached_session(&self, cache_key: &CacheKey) -> Option<SessionVariables> {
+        self.cache
+            .lock()
+            .unwrap()
+            .get(cache_key)
+            .filter(|cached| cached.cached_at.elapsed() < self.cache_ttl)
+            .map(|cached| cached.session_variables.clone())
+    }
+
+    async fn call_webhook(&self, headers: &HeaderMap) -> Result<SessionVariables, GQLRSError> {
+        let forwarded_headers = forwarded_headers(headers);
+
+        let client = awc::Client::builder().timeout(WEBHOOK_TIMEOUT).finish();
+        let response = match self.mode {
+            AuthHookMode::Get => {
+                let mut request = client.get(&self.url);
+                for (name, value) in forwarded_headers {
+                    request = request.header(name, value);
+                }
+                request.send().await
+            }
+            AuthHookMode::Post => {
+                let headers: serde_json::Map<String, Value> = forwarded_headers
+                    .into_iter()
+                    .map(|(name, value)| (String::from(name), Value::from(value)))
+                    .collect();
+                client
+                    .post(&self.url)
+                    .send_json(&serde_json::json!({ "headers": headers }))
+                    .await
+            }
+        };
+        let mut response = response.map_err(|err| auth_hook_error(err.to_string()))?;
+
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::UNAUTHORIZED => {
+                return Err(GQLRSError::new(GQLRSErrorType::AccessDenied(String::from(
+                    "the authentication hook unauthorized this request",
+                ))))
+            }
+            status => {
+                return Err(auth_hook_error(format!(
+                    "the webhook responded with the status {}",
+                    status
+                )))
+            }
+        }
+
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|err| auth_hook_error(err.to_string()))?;
+        session_from_webhook_response(&body)
+    }
+}
+
+/// The headers of a request that the webhook gets to see
+pub fn forwarded_headers(headers: &HeaderMap) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            !IGNORED_HEADERS.contains(&name.as_str())
+                && !name.as_str().starts_with("sec-websocket-")
+        })
+        .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.as_str(), value)))
+        .collect()
+}
+
+/// A session is cached by the headers that the webhook tells the clients apart by, i.e.
+/// the credential headers along with the configured auth headers, so that the headers that
+/// change from one request to the next (like tracing headers) don't defeat the cache
+fn cache_key(headers: &HeaderMap, auth_headers: &[String]) -> CacheKey {
+    let mut cache_key: CacheKey = forwarded_headers(headers)
+        .into_iter()
+        .filter(|(name, _)| {
+            CREDENTIAL_HEADERS.contains(name) || auth_headers.iter().any(|header| header == name)
+        })
+        .map(|(name, value)| (String::from(name), String::from(value)))
+        .collect();
+    cache_key.sort();
+    cache_key
+}
+
+/// The session variables in the response of the webhook, which has to pick the role
+fn session_from_webhook_response(body: &Value) -> Result<SessionVariables, GQLRSError> {
+    let response = body.as_object().ok_or_else(|| {
+        auth_hook_error(String::from(
+            "the webhook should respond with a JSON object",
+        ))
+    })?;
+
+    let mut session_variables = SessionVariables::default();
+    let mut has_role = false;
+    for (name, value) in response
+        .iter()
+        .filter(|(name, _)| is_session_variable(name))
+    {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Number(value) => value.to_string(),
+            Value::Bool(value) => value.to_string(),
+            _ => {
+                return Err(auth_hook_error(format!(
+                    "the session variable `{}` should be a string",
+                    name
+                )))
+            }
+        };
+        has_role = has_role || name.to_lowercase() == ROLE_SESSION_VARIABLE;
+        session_variables.insert(name, &value)?;
+    }
+
+    if !has_role {
+        return Err(auth_hook_error(format!(
+            "the webhook should respond with a `{}`",
+            ROLE_SESSION_VARIABLE
+        )));
+    }
+
+    Ok(session_variables)
+}
+
+#[test]
+fn session_from_webhook_response_needs_a_role() {
+    let session_variables = session_from_webhook_response(&serde_json::json!({
+        "X-Hasura-Role": "user",
+        "X-Hasura-User-Id": 3,
+        "expires": "never"
+    }))
+    .unwrap();
+    assert_eq!(session_variables.role(), "user");
+    assert_eq!(session_variables.get("x-hasura-user-id"), Some("3"));
+    assert_eq!(session_variables.get("expires"), None);
+
+    assert!(
+        session_from_webhook_response(&serde_json::json!({ "X-Hasura-User-Id": "3" })).is_err()
+    );
+    assert!(session_from_webhook_response(&serde_json::json!(["user"])).is_err());
+}
+
+#[test]
+fn cache_key_has_the_credential_and_auth_headers() {
+    use actix_web::http::{HeaderName, HeaderValue};
+
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    };
+    let auth_headers = vec![String::from("x-api-key")];
+
+    let request_headers = headers(&[
+        ("authorization", "Bearer user-3"),
+        ("x-api-key", "abc"),
+        ("x-request-id", "1"),
+        ("user-agent", "curl"),
+        ("sec-websocket-key", "dGhlIHNhbXBsZQ=="),
+    ]);
+    assert_eq!(
+        cache_key(&request_headers, &auth_headers),
+        vec![
+            (String::from("authorization"), String::from("Bearer user-3")),
+            (String::from("x-api-key"), String::from("abc")),
+        ]
+    );
+    assert_eq!(
+        cache_key(&request_headers, &[]),
+        vec![(String::from("authorization"), String::from("Bearer user-3"))]
+    );
+
+    // the clients that only differ by a header that the webhook tells them apart by aren't
+    // the same, while the ones that only differ by any other header are
+    assert_ne!(
+        cache_key(&request_headers, &auth_headers),
+        cache_key(
+            &headers(&[("authorization", "Bearer user-3")]),
+            &auth_headers
+        )
+    );
+    assert_eq!(
+        cache_key(&request_headers, &auth_headers),
+        cache_key(
+            &headers(&[
+                ("x-api-key", "abc"),
+                ("x-request-id", "2"),
+                ("authorization", "Bearer user-3"),
+            ]),
+            &auth_headers
+        )
+    );
+}